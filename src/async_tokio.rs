@@ -0,0 +1,30 @@
+use std::cell::RefCell;
+use std::future::Future;
+use super::Library;
+
+tokio::task_local!(pub static TASK_LIBRARY: RefCell<Library>);
+
+/// Returns true if the caller is running inside of a future
+/// wrapped by `with_task_profiling`.
+pub fn in_task() -> bool {
+    TASK_LIBRARY.try_with(|_| ()).is_ok()
+}
+
+/// Records all of the spans started while polling `fut` into a
+/// `Library` that belongs to the task rather than to the thread.
+///
+/// Work-stealing runtimes are free to poll a future on a different
+/// worker thread every time it wakes up, so a span started in one
+/// poll might be ended on a completely different thread.  Inside of
+/// `with_task_profiling` that is fine: all of the task's spans stay
+/// together in one timeline no matter which thread polls it.
+///
+/// When the returned future is dropped, the task's spans are
+/// committed just like a thread's are when it exits.
+pub fn with_task_profiling<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+    let mut library = Library::new();
+    // The thread that happens to create the task doesn't say anything
+    // about where it will run.
+    library.name = None;
+    TASK_LIBRARY.scope(RefCell::new(library), fut)
+}
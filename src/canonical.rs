@@ -0,0 +1,33 @@
+use sha2::{Digest, Sha256};
+use super::Span;
+
+/// Hashes the shape of a span tree, ignoring all timing information.
+///
+/// The spans (and the children of every span) are sorted by name
+/// before hashing, so two runs that make the same calls produce the
+/// same digest even if their timings or the order in which sibling
+/// spans ran differ.  This makes it cheap to check in CI whether the
+/// structure of a profile changed.
+pub fn canonical_digest(spans: &[Span]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(encode_all(spans));
+    hasher.finalize().into()
+}
+
+fn encode_all(spans: &[Span]) -> Vec<u8> {
+    let mut encoded: Vec<(&str, Vec<u8>)> = spans
+        .iter()
+        .map(|span| (&span.name[..], encode(span)))
+        .collect();
+    encoded.sort();
+    encoded.into_iter().flat_map(|(_, bytes)| bytes).collect()
+}
+
+fn encode(span: &Span) -> Vec<u8> {
+    let mut out = vec![];
+    out.extend_from_slice(&span.depth.to_le_bytes());
+    out.extend_from_slice(&(span.name.len() as u64).to_le_bytes());
+    out.extend_from_slice(span.name.as_bytes());
+    out.extend(encode_all(&span.children));
+    out
+}
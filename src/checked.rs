@@ -0,0 +1,76 @@
+//! Versions of Flame's recording functions that never panic.
+//!
+//! Every function in this module mirrors the function of the same
+//! name at the root of the crate, but reports misuse (like ending a
+//! span that isn't running) by returning a `FlameError` instead of
+//! panicking.  This is useful when instrumenting code that you don't
+//! fully control, where a mismatched name shouldn't take down the
+//! whole process.
+
+use super::{FlameError, NoteLevel, StrCow};
+
+/// Starts a new Span
+pub fn start<S: Into<StrCow>>(name: S) -> Result<(), FlameError> {
+    if !super::is_enabled() {
+        return Err(FlameError::Disabled);
+    }
+    super::start(name);
+    Ok(())
+}
+
+/// Ends the current Span and returns the number
+/// of nanoseconds that passed.
+///
+/// If `name` doesn't match the current Span, nothing is ended.
+pub fn end<S: Into<StrCow>>(name: S) -> Result<u64, FlameError> {
+    super::try_end_impl(name.into(), false)
+}
+
+/// Records a note on the current Span.
+pub fn note<N: Into<StrCow>, D: Into<StrCow>>(name: N, description: Option<D>) -> Result<(), FlameError> {
+    super::try_note_impl(name.into(), description.map(Into::into), None, NoteLevel::Info, false)
+}
+
+/// Starts and ends a `Span` that lasts for the duration of the
+/// function `f`.
+///
+/// The span is ended even if `f` panics, or leaves spans running
+/// (which are ended with it, and reported as a `NameMismatch`).
+pub fn span_of<S, F, R>(name: S, f: F) -> Result<R, FlameError> where
+S: Into<StrCow>,
+F: FnOnce() -> R
+{
+    let name = name.into();
+    let depth = super::running_depth();
+    start(name.clone())?;
+    let mut guard = EndOnDrop(Some((name, depth)));
+    let r = f();
+    let (name, depth) = guard.0.take().unwrap();
+    end_span_of(name, depth)?;
+    Ok(r)
+}
+
+/// Ends the span `name` that `span_of` started at `depth`.  If `f`
+/// left spans running inside of it, they're ended along with it, and
+/// the mismatch is still reported.
+fn end_span_of(name: StrCow, depth: usize) -> Result<u64, FlameError> {
+    match end(name.clone()) {
+        Err(error @ FlameError::NameMismatch { .. }) => {
+            super::end_from_depth(depth, name);
+            Err(error)
+        }
+        result => result,
+    }
+}
+
+/// Ends the span started by `span_of` if `f` unwinds instead of
+/// returning.
+struct EndOnDrop(Option<(StrCow, usize)>);
+
+impl Drop for EndOnDrop {
+    fn drop(&mut self) {
+        if let Some((name, depth)) = self.0.take() {
+            let _ = end_span_of(name, depth);
+        }
+    }
+}
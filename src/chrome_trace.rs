@@ -0,0 +1,123 @@
+//! Export to the Trace Event format that `chrome://tracing` and the
+//! Perfetto UI read.
+
+use std::io::Write;
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_json;
+use super::{align_threads, collapse_below_depth, threads, AttrValue, Span, StrCow, Thread};
+
+/// A single entry in the JSON array of a trace.
+#[derive(Serialize)]
+struct TraceEvent<'a> {
+    name: &'a str,
+    ph: &'static str,
+    /// Microseconds, as the format expects
+    ts: f64,
+    pid: u32,
+    tid: usize,
+    /// The scope of an instant event; always the thread
+    #[serde(skip_serializing_if = "Option::is_none")]
+    s: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    args: Option<Args<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Args<'a> {
+    Note { description: &'a StrCow },
+    Span(Attributes<'a>),
+}
+
+/// The attributes of a span, written as an object.
+struct Attributes<'a>(&'a [(StrCow, AttrValue)]);
+
+impl<'a> Serialize for Attributes<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (key, value) in self.0 {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+/// Options for `write_chrome_trace_with_options`.
+#[derive(Debug, Clone, Default)]
+pub struct ChromeTraceOptions {
+    /// Collapse every span that is this deep into a single event
+    /// (see `collapse_below_depth`)
+    pub collapse_below_depth: Option<u16>,
+}
+
+/// Writes every thread's spans as a Chrome trace: a JSON array of
+/// duration events (`"ph": "B"` and `"E"`) for the spans, and thread
+/// scoped instant events (`"ph": "i"`) for their notes.  A span's
+/// attributes become the `args` of its `"B"` event.
+///
+/// Every event has a `pid` of 0 and the recording thread's id as its
+/// `tid`.  The threads are put on a common timeline with
+/// `align_threads`.
+pub fn dump_chrome_trace<W: Write>(out: &mut W) -> serde_json::Result<()> {
+    write_chrome_trace(out, &threads())
+}
+
+/// Like `dump_chrome_trace`, but writes the given threads instead of
+/// what is currently being recorded.
+pub fn write_chrome_trace<W: Write>(out: &mut W, threads: &[Thread]) -> serde_json::Result<()> {
+    write_chrome_trace_with_options(out, threads, &ChromeTraceOptions::default())
+}
+
+/// Like `write_chrome_trace`, with the given options.
+pub fn write_chrome_trace_with_options<W: Write>(out: &mut W, threads: &[Thread], options: &ChromeTraceOptions) -> serde_json::Result<()> {
+    fn push<'a>(span: &'a Span, tid: usize, events: &mut Vec<TraceEvent<'a>>) {
+        events.push(TraceEvent {
+            name: &span.name,
+            ph: "B",
+            ts: span.start_ns as f64 / 1000.0,
+            pid: 0,
+            tid,
+            s: None,
+            args: if span.attributes.is_empty() { None } else { Some(Args::Span(Attributes(&span.attributes))) },
+        });
+        for note in &span.notes {
+            events.push(TraceEvent {
+                name: &note.name,
+                ph: "i",
+                ts: note.instant as f64 / 1000.0,
+                pid: 0,
+                tid,
+                s: Some("t"),
+                args: note.description.as_ref().map(|description| Args::Note { description }),
+            });
+        }
+        for child in &span.children {
+            push(child, tid, events);
+        }
+        events.push(TraceEvent {
+            name: &span.name,
+            ph: "E",
+            ts: span.end_ns as f64 / 1000.0,
+            pid: 0,
+            tid,
+            s: None,
+            args: None,
+        });
+    }
+
+    let mut threads = threads.to_vec();
+    align_threads(&mut threads);
+    if let Some(max_depth) = options.collapse_below_depth {
+        for thread in &mut threads {
+            thread.spans = collapse_below_depth(::std::mem::take(&mut thread.spans), max_depth);
+        }
+    }
+
+    let mut events = vec![];
+    for thread in &threads {
+        for span in &thread.spans {
+            push(span, thread.id, &mut events);
+        }
+    }
+    serde_json::to_writer(out, &events)
+}
@@ -0,0 +1,171 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use super::{convert_events_to_span, ns_to_stamp, Event, Note, NoteLevel, Span, StrCow};
+
+/// A recorder that doesn't rely on anything global: no thread locals,
+/// no locks and no clock.
+///
+/// The caller owns the `Collector`, passes it around by `&mut`, and
+/// supplies the time (in nanoseconds since any epoch it likes) for
+/// everything that it records.  This makes it usable where the
+/// functions at the root of the crate aren't, e.g. in interrupt
+/// handlers or on targets without threads or `Instant`, and it's
+/// available without the `std` feature.
+///
+/// A collector holds at most `capacity` spans, and never grows past
+/// that; further spans are silently dropped (but must still be ended,
+/// as usual).
+pub struct Collector {
+    events: Vec<Event>,
+    capacity: usize,
+    cursor: Cursor,
+    next_note_seq: u64,
+}
+
+impl Collector {
+    /// Creates a collector with room for `capacity` spans, which are
+    /// allocated up front.
+    pub fn with_capacity(capacity: usize) -> Collector {
+        Collector {
+            events: Vec::with_capacity(capacity),
+            capacity,
+            cursor: Cursor::new(),
+            next_note_seq: 0,
+        }
+    }
+
+    /// Starts a new Span at `now_ns`, unless the collector is full.
+    pub fn start<S: Into<StrCow>>(&mut self, name: S, now_ns: u64) {
+        let full = self.events.len() == self.capacity;
+        if let Some(event) = self.cursor.start(self.events.len(), full, name.into(), now_ns) {
+            self.events.push(event);
+        }
+    }
+
+    /// Ends the current Span at `now_ns` and returns the number of
+    /// nanoseconds that passed.
+    ///
+    /// Returns 0 if the span was dropped because the collector was full.
+    pub fn end<S: Into<StrCow>>(&mut self, name: S, now_ns: u64) -> u64 {
+        self.cursor.end("Collector", &mut self.events, name.into(), now_ns)
+    }
+
+    /// Records a note at `now_ns` on the current Span.  Nothing is
+    /// recorded if the span was dropped because the collector was full.
+    pub fn note<N: Into<StrCow>, D: Into<StrCow>>(&mut self, name: N, description: Option<D>, now_ns: u64) {
+        let name = name.into();
+        if self.cursor.dropped_running > 0 {
+            return;
+        }
+
+        let id = match self.cursor.current {
+            Some(id) => id,
+            None => panic!("Collector::note({:?}) called without a currently running span!", &name),
+        };
+
+        self.events[id as usize].notes.push(Note {
+            name,
+            description: description.map(Into::into),
+            instant: now_ns,
+            seq: self.next_note_seq,
+            level: NoteLevel::Info,
+            track: None,
+            _priv: (),
+        });
+        self.next_note_seq += 1;
+    }
+
+    /// The number of spans that have been recorded.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Throws away everything that was recorded, keeping the
+    /// allocated room for spans.
+    pub fn clear(&mut self) {
+        self.events.clear();
+        self.cursor = Cursor::new();
+        self.next_note_seq = 0;
+    }
+
+    /// Returns the recorded spans.
+    pub fn spans(&self) -> Vec<Span> {
+        convert_events_to_span(self.events.iter())
+    }
+}
+
+/// Keeps track of the running spans for `Collector` and
+/// `FixedSession`, which only differ in where they keep their events.
+pub(crate) struct Cursor {
+    /// The id of the innermost running span
+    pub(crate) current: Option<u32>,
+    /// The number of running spans that were dropped because there
+    /// was no room for them
+    pub(crate) dropped_running: usize,
+}
+
+impl Cursor {
+    pub(crate) fn new() -> Cursor {
+        Cursor { current: None, dropped_running: 0 }
+    }
+
+    /// Starts a span as the event with id `id`, and returns that event
+    /// for the caller to store, or `None` if it's dropped because
+    /// there's no room left (`full`).
+    pub(crate) fn start(&mut self, id: usize, full: bool, name: StrCow, now_ns: u64) -> Option<Event> {
+        if full {
+            self.dropped_running += 1;
+            return None;
+        }
+
+        let id = id as u32;
+        let event = Event {
+            id,
+            parent: self.current,
+            name,
+            collapse: false,
+            start: ns_to_stamp(now_ns),
+            end: None,
+            #[cfg(feature = "std")]
+            async_end: None,
+            delta: None,
+            notes: Vec::new(),
+            attributes: Vec::new(),
+            metrics: BTreeMap::new(),
+            wait_reason: None,
+            annotation: false,
+            #[cfg(feature = "rss")]
+            rss: Default::default(),
+        };
+        self.current = Some(id);
+        Some(event)
+    }
+
+    /// Ends the current span, which is in `events`, at `now_ns` and
+    /// returns the number of nanoseconds that passed, or 0 if the span
+    /// was dropped.  `owner` names the recorder in panic messages.
+    pub(crate) fn end(&mut self, owner: &str, events: &mut [Event], name: StrCow, now_ns: u64) -> u64 {
+        if self.dropped_running > 0 {
+            self.dropped_running -= 1;
+            return 0;
+        }
+
+        let id = match self.current {
+            Some(id) => id,
+            None => panic!("{}::end({:?}) called without a currently running span!", owner, &name),
+        };
+
+        let event = &mut events[id as usize];
+        if event.name != name {
+            panic!("{}::end({}) attempted to end {}", owner, &name, event.name);
+        }
+
+        let delta = event.finish(now_ns);
+        self.current = event.parent;
+        delta
+    }
+}
@@ -0,0 +1,44 @@
+use std::io::{Result as IoResult, Write};
+use super::{align_threads, threads, Span};
+
+/// Writes every thread's spans as CSV, with one row per span, for
+/// loading into a spreadsheet or a dataframe.
+///
+/// The columns are `thread_id,depth,name,start_ns,end_ns,delta_ns,
+/// self_ns,note_count`, and the first row is a header naming them.
+/// Spans are written depth first, so each one comes right after its
+/// parent.  The threads are put on a common timeline with
+/// `align_threads`, and names are quoted as described in RFC 4180
+/// where needed.
+pub fn dump_csv_to_writer<W: Write>(mut out: W) -> IoResult<()> {
+    fn write_span<W: Write>(span: &Span, thread_id: usize, out: &mut W) -> IoResult<()> {
+        writeln!(out, "{},{},{},{},{},{},{},{}",
+                 thread_id, span.depth, escape(&span.name), span.start_ns, span.end_ns,
+                 span.delta, span.self_ns(), span.notes.len())?;
+        for child in &span.children {
+            write_span(child, thread_id, out)?;
+        }
+        Ok(())
+    }
+
+    let mut threads = threads();
+    align_threads(&mut threads);
+
+    writeln!(out, "thread_id,depth,name,start_ns,end_ns,delta_ns,self_ns,note_count")?;
+    for thread in &threads {
+        for span in &thread.spans {
+            write_span(span, thread.id, &mut out)?;
+        }
+    }
+    Ok(())
+}
+
+/// Quotes `field` if it contains a comma, a quote or a line break,
+/// doubling any quotes inside of it.
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
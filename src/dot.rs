@@ -0,0 +1,65 @@
+use std::io::{Result as IoResult, Write};
+use super::{collapse_below_depth, Span};
+
+/// Options for `dump_dot_with_options`.
+#[derive(Debug, Clone, Default)]
+pub struct DotOptions {
+    /// Collapse every span that is this deep into a single box
+    /// (see `collapse_below_depth`)
+    pub collapse_below_depth: Option<u16>,
+}
+
+/// Writes `spans` as a Graphviz `digraph`, which `dot -Tsvg` turns
+/// into a call graph.
+///
+/// Every span becomes a box labelled with its name and total time in
+/// milliseconds, with an edge from each span to each of its children.
+/// The boxes are filled from white to red according to the span's
+/// self time, relative to the span with the most self time.  Passing
+/// in `merged_spans()` gives one box per distinct call path.
+pub fn dump_dot_to_writer<W: Write>(mut out: W, spans: &[Span]) -> IoResult<()> {
+    fn max_self_ns(spans: &[Span]) -> u64 {
+        spans.iter().map(|span| span.self_ns().max(max_self_ns(&span.children))).max().unwrap_or(0)
+    }
+
+    fn write_node<W: Write>(span: &Span, parent: Option<usize>, next_id: &mut usize, max_self_ns: u64, out: &mut W) -> IoResult<()> {
+        let id = *next_id;
+        *next_id += 1;
+
+        // 255 for no self time, down to 0 for the most
+        let intensity = if max_self_ns == 0 { 0.0 } else { span.self_ns() as f64 / max_self_ns as f64 };
+        let shade = 255 - (intensity * 255.0).round() as u8;
+        writeln!(out, "    n{} [label=\"{}\\n{}ms\", fillcolor=\"#ff{:02x}{:02x}\"];",
+                 id, escape(&span.name), span.delta as f64 / 1000000.0, shade, shade)?;
+        if let Some(parent) = parent {
+            writeln!(out, "    n{} -> n{};", parent, id)?;
+        }
+
+        for child in &span.children {
+            write_node(child, Some(id), next_id, max_self_ns, out)?;
+        }
+        Ok(())
+    }
+
+    let max_self_ns = max_self_ns(spans);
+    writeln!(out, "digraph flame {{")?;
+    writeln!(out, "    node [shape=box, style=filled];")?;
+    let mut next_id = 0;
+    for span in spans {
+        write_node(span, None, &mut next_id, max_self_ns, &mut out)?;
+    }
+    writeln!(out, "}}")
+}
+
+/// Like `dump_dot_to_writer`, with the given options.
+pub fn dump_dot_with_options<W: Write>(out: W, spans: &[Span], options: &DotOptions) -> IoResult<()> {
+    match options.collapse_below_depth {
+        Some(max_depth) => dump_dot_to_writer(out, &collapse_below_depth(spans.to_vec(), max_depth)),
+        None => dump_dot_to_writer(out, spans),
+    }
+}
+
+/// Escapes `name` for use inside of a quoted DOT string.
+fn escape(name: &str) -> String {
+    name.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n").replace('\r', "")
+}
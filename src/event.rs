@@ -0,0 +1,391 @@
+use alloc::borrow::Cow;
+use alloc::collections::BTreeMap;
+#[cfg(feature = "std")]
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use core::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "rss")]
+use rss::Rss;
+
+pub type StrCow = Cow<'static, str>;
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub(crate) struct Event {
+    pub(crate) id: u32,
+    pub(crate) parent: Option<u32>,
+    pub(crate) name: StrCow,
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub(crate) collapse: bool,
+    #[cfg_attr(feature = "json", serde(rename = "start_ns", serialize_with = "serialize_stamp"))]
+    pub(crate) start: Stamp,
+    #[cfg_attr(feature = "json", serde(rename = "end_ns", serialize_with = "serialize_optional_stamp"))]
+    pub(crate) end: Option<Stamp>,
+    /// Where the `AsyncSpanGuard` of a span started with `start_async`
+    /// puts the time that it ended at, from whichever thread it's on.
+    /// `u64::MAX` until then.
+    #[cfg(feature = "std")]
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub(crate) async_end: Option<Arc<AtomicU64>>,
+    #[cfg_attr(feature = "json", serde(serialize_with = "serialize_optional_stamp"))]
+    pub(crate) delta: Option<Stamp>,
+    pub(crate) notes: Vec<Note>,
+    pub(crate) attributes: Vec<(StrCow, AttrValue)>,
+    pub(crate) metrics: BTreeMap<StrCow, f64>,
+    pub(crate) wait_reason: Option<StrCow>,
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub(crate) annotation: bool,
+    #[cfg(feature = "rss")]
+    #[cfg_attr(feature = "json", serde(flatten))]
+    pub(crate) rss: Rss,
+}
+
+impl Event {
+    pub(crate) fn start_ns(&self) -> u64 {
+        stamp_to_ns(self.start)
+    }
+
+    pub(crate) fn end_ns(&self) -> Option<u64> {
+        self.ended_at().map(stamp_to_ns)
+    }
+
+    pub(crate) fn delta_ns(&self) -> Option<u64> {
+        self.delta.or_else(|| self.ended_at().map(|end| end.saturating_sub(self.start))).map(stamp_to_ns)
+    }
+
+    /// The time that the event ended at, including the end of an
+    /// async span that was set by its guard.
+    #[cfg(feature = "std")]
+    pub(crate) fn ended_at(&self) -> Option<Stamp> {
+        self.end.or_else(|| {
+            let end_ns = self.async_end.as_ref()?.load(Ordering::SeqCst);
+            if end_ns == u64::MAX { None } else { Some(ns_to_stamp(end_ns)) }
+        })
+    }
+
+    /// Without `std` there are no async spans, which need atomics
+    /// that not every target has.
+    #[cfg(not(feature = "std"))]
+    pub(crate) fn ended_at(&self) -> Option<Stamp> {
+        self.end
+    }
+
+    /// Ends the event at `end_ns` and returns the number of
+    /// nanoseconds that it took.
+    pub(crate) fn finish(&mut self, end_ns: u64) -> u64 {
+        let end = ns_to_stamp(end_ns);
+        let delta = end.saturating_sub(self.start);
+        self.end = Some(end);
+        self.delta = Some(delta);
+        #[cfg(feature = "rss")]
+        self.rss.finish();
+        stamp_to_ns(delta)
+    }
+}
+
+/// A timestamp or duration, as stored in an `Event`.
+///
+/// With the `micros` feature, this is a number of microseconds in a
+/// `u32` instead of nanoseconds in a `u64`, which makes every event
+/// smaller.  The price is that timings are only accurate to the
+/// microsecond, and that timestamps more than `u32::MAX`
+/// microseconds (about 71 minutes) after the thread's epoch are
+/// clamped to that limit.
+#[cfg(not(feature = "micros"))]
+pub(crate) type Stamp = u64;
+#[cfg(feature = "micros")]
+pub(crate) type Stamp = u32;
+
+#[cfg(not(feature = "micros"))]
+pub(crate) fn ns_to_stamp(ns: u64) -> Stamp {
+    ns
+}
+
+#[cfg(not(feature = "micros"))]
+pub(crate) fn stamp_to_ns(stamp: Stamp) -> u64 {
+    stamp
+}
+
+#[cfg(feature = "micros")]
+pub(crate) fn ns_to_stamp(ns: u64) -> Stamp {
+    ::core::cmp::min(ns / 1000, u64::from(u32::MAX)) as u32
+}
+
+#[cfg(feature = "micros")]
+pub(crate) fn stamp_to_ns(stamp: Stamp) -> u64 {
+    u64::from(stamp) * 1000
+}
+
+#[cfg(feature = "json")]
+fn serialize_stamp<S: serde::Serializer>(stamp: &Stamp, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(stamp_to_ns(*stamp))
+}
+
+#[cfg(feature = "json")]
+fn serialize_optional_stamp<S: serde::Serializer>(stamp: &Option<Stamp>, serializer: S) -> Result<S::Ok, S::Error> {
+    match *stamp {
+        Some(stamp) => serializer.serialize_some(&stamp_to_ns(stamp)),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// A named timespan.
+///
+/// The span is the most important feature of Flame.  It denotes
+/// a chunk of time that is important to you.
+///
+/// The Span records
+/// * Start and stop time
+/// * A list of children (also called sub-spans)
+/// * A list of notes
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+#[allow(clippy::manual_non_exhaustive)]
+pub struct Span {
+    /// The name of the span
+    pub name: StrCow,
+    /// The timestamp of the start of the span
+    pub start_ns: u64,
+    /// The timestamp of the end of the span
+    pub end_ns: u64,
+    /// The time that ellapsed between start_ns and end_ns
+    pub delta: u64,
+    /// How deep this span is in the tree
+    pub depth: u16,
+    /// A list of spans that occurred inside this one
+    pub children: Vec<Span>,
+    /// A list of notes that occurred inside this span
+    pub notes: Vec<Note>,
+    /// Typed metadata that was attached to the span with `attr`, in
+    /// the order that it was attached in
+    #[cfg_attr(feature = "json", serde(default))]
+    pub attributes: Vec<(StrCow, AttrValue)>,
+    /// Named numbers that were attached to the span with
+    /// `set_span_metric`
+    #[cfg_attr(feature = "json", serde(default))]
+    pub metrics: BTreeMap<StrCow, f64>,
+    /// How many recorded spans were merged together to make this one.
+    ///
+    /// This is 1 unless the span was collapsed (see `end_collapse`).
+    pub collapse_count: u64,
+    /// What the span was waiting on, if it was recorded with `pause_for`
+    #[cfg_attr(feature = "json", serde(default))]
+    pub wait_reason: Option<StrCow>,
+    /// True if the span was recorded with `note_as_span`, and is
+    /// meant to be shown like a note
+    #[cfg_attr(feature = "json", serde(default))]
+    pub annotation: bool,
+    /// How the process' memory use changed while the span was running
+    #[cfg(feature = "rss")]
+    #[cfg_attr(feature = "json", serde(flatten, default))]
+    pub rss: Rss,
+    /// The time spent in this span, including its children.
+    /// `None` until computed by `annotate_times`.
+    #[cfg_attr(feature = "json", serde(default))]
+    pub inclusive_ns: Option<u64>,
+    /// The time spent in this span itself, outside of its children.
+    /// `None` until computed by `annotate_times`.
+    #[cfg_attr(feature = "json", serde(default))]
+    pub exclusive_ns: Option<u64>,
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub(crate) collapsable: bool,
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub(crate) _priv: (),
+}
+
+/// A note for use in debugging.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+#[allow(clippy::manual_non_exhaustive)]
+pub struct Note {
+    /// A short name describing what happened at some instant in time
+    pub name: StrCow,
+    /// A longer description
+    pub description: Option<StrCow>,
+    /// The time that the note was added
+    pub instant: u64,
+    /// The order in which notes were added on this thread.
+    ///
+    /// Clocks have a limited resolution, so several notes can share
+    /// the same `instant`; this tells them apart.
+    #[cfg_attr(feature = "json", serde(default))]
+    pub seq: u64,
+    /// How important the note is
+    #[cfg_attr(feature = "json", serde(default))]
+    pub level: NoteLevel,
+    /// The named sub-timeline that the note belongs to, if any.
+    /// See `note_on_track`.
+    #[cfg_attr(feature = "json", serde(default))]
+    pub track: Option<StrCow>,
+    #[cfg_attr(feature = "json", serde(skip))]
+    pub(crate) _priv: (),
+}
+
+/// The value of an attribute attached to a span with `attr`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "json", serde(untagged))]
+pub enum AttrValue {
+    Str(StrCow),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    Bool(bool),
+}
+
+/// How important a `Note` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub enum NoteLevel {
+    /// Something interesting happened
+    #[default]
+    Info,
+    /// Something suspicious happened
+    Warn,
+    /// Something went wrong
+    Error,
+}
+
+pub(crate) fn convert_events_to_span<'a, I>(events: I) -> Vec<Span>
+where I: Iterator<Item = &'a Event> {
+    // Events usually directly follow their parent, but spans that
+    // were given an explicit parent can show up anywhere, so group
+    // everything by parent up front.
+    let mut roots = vec![];
+    let mut children: BTreeMap<u32, Vec<&Event>> = BTreeMap::new();
+    for event in events {
+        match event.parent {
+            Some(parent) => children.entry(parent).or_default().push(event),
+            None => roots.push(event),
+        }
+    }
+
+    // This is done with an explicit stack rather than by recursing,
+    // since spans can be nested deeper than the native stack allows.
+    let mut v = vec![];
+    for root in roots {
+        let mut stack = vec![PendingSpan::new(root, 0)];
+        while let Some(top) = stack.last_mut() {
+            let next = children.get(&top.event.id).and_then(|next| next.get(top.next_child));
+            if let Some(next) = next {
+                top.next_child += 1;
+                let depth = top.child_depth;
+                stack.push(PendingSpan::new(next, depth));
+                continue;
+            }
+
+            let done = stack.pop().unwrap();
+            match stack.last_mut() {
+                Some(parent) => done.finish(&mut parent.converted),
+                None => done.finish(&mut v),
+            }
+        }
+    }
+    v
+}
+
+/// An event that is being converted into a `Span`, along with its
+/// children that have been converted so far.
+struct PendingSpan<'a> {
+    event: &'a Event,
+    /// `None` if the event hasn't ended yet
+    span: Option<Span>,
+    child_depth: u16,
+    next_child: usize,
+    converted: Vec<Span>,
+}
+
+impl<'a> PendingSpan<'a> {
+    fn new(event: &'a Event, depth: u16) -> PendingSpan<'a> {
+        let span = match (event.end_ns(), event.delta_ns()) {
+            (Some(end_ns), Some(delta)) => Some(Span {
+                name: event.name.clone(),
+                start_ns: event.start_ns(),
+                end_ns,
+                delta,
+                depth,
+                children: vec![],
+                notes: event.notes.clone(),
+                attributes: event.attributes.clone(),
+                metrics: event.metrics.clone(),
+                collapse_count: 1,
+                wait_reason: event.wait_reason.clone(),
+                annotation: event.annotation,
+                #[cfg(feature = "rss")]
+                rss: event.rss,
+                inclusive_ns: None,
+                exclusive_ns: None,
+                collapsable: event.collapse,
+                _priv: ()
+            }),
+            _ => None,
+        };
+        // Children of an event that hasn't ended take its place, so
+        // they stay at its depth.  Depths past `u16::MAX` saturate.
+        let child_depth = if span.is_some() { depth.saturating_add(1) } else { depth };
+
+        PendingSpan { event, span, child_depth, next_child: 0, converted: vec![] }
+    }
+
+    /// Pushes the finished span onto `out`, or its children if the
+    /// event hasn't ended yet.
+    fn finish(self, out: &mut Vec<Span>) {
+        let mut span = match self.span {
+            Some(span) => span,
+            None => {
+                out.extend(self.converted);
+                return;
+            }
+        };
+
+        for child in self.converted {
+            // Try to collapse with the previous span
+            if !span.children.is_empty() && child.collapsable && child.children.is_empty() {
+                let last = span.children.last_mut().unwrap();
+                if last.name == child.name && last.depth == child.depth {
+                    last.end_ns = child.end_ns;
+                    last.delta += child.delta;
+                    last.collapse_count += child.collapse_count;
+                    #[cfg(feature = "rss")]
+                    last.rss.merge(&child.rss);
+                    continue;
+                }
+            }
+
+            // Otherwise, it's a new node
+            span.children.push(child);
+        }
+
+        out.push(span);
+    }
+}
+
+impl Span {
+    /// Returns true if other spans were collapsed into this one.
+    ///
+    /// When that happens `end_ns - start_ns` covers all of the
+    /// collapsed spans (and the time between them), while `delta`
+    /// is only the sum of their durations.
+    pub fn is_collapsed(&self) -> bool {
+        self.collapse_count > 1
+    }
+
+    /// Returns the time spent in this span itself, outside of its
+    /// children: its `delta` minus theirs.
+    ///
+    /// This is 0 rather than negative when the children add up to more
+    /// than the span, which collapsed children can do.
+    pub fn self_ns(&self) -> u64 {
+        let children: u64 = self.children.iter().map(|child| child.delta).sum();
+        self.delta.saturating_sub(children)
+    }
+
+    /// Returns the notes on this span in the order that they were
+    /// recorded in, even if several of them share an `instant`.
+    pub fn notes_sorted(&self) -> Vec<&Note> {
+        let mut notes: Vec<&Note> = self.notes.iter().collect();
+        notes.sort_by_key(|note| (note.instant, note.seq));
+        notes
+    }
+}
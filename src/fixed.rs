@@ -0,0 +1,87 @@
+use std::mem::MaybeUninit;
+use std::time::Instant;
+use super::{Event, Span, StrCow, convert_events_to_span, ns_since_epoch};
+use collector::Cursor;
+
+/// A recording session that can hold up to `N` spans without
+/// allocating.
+///
+/// The events are stored inline, so a `FixedSession` can live on the
+/// stack.  Once `N` spans have been started, any further spans are
+/// silently dropped (but must still be ended, as usual).  Span names
+/// should be `&'static str`s to avoid allocating for them.
+///
+/// Unlike the functions at the root of the crate, a `FixedSession`
+/// is not tied to the current thread; it only records what is
+/// explicitly started and ended on it.
+pub struct FixedSession<const N: usize> {
+    events: [MaybeUninit<Event>; N],
+    len: usize,
+    cursor: Cursor,
+    epoch: Instant,
+}
+
+impl<const N: usize> FixedSession<N> {
+    pub fn new() -> FixedSession<N> {
+        FixedSession {
+            // An array of `MaybeUninit`s doesn't need to be initialized.
+            events: unsafe { MaybeUninit::uninit().assume_init() },
+            len: 0,
+            cursor: Cursor::new(),
+            epoch: Instant::now(),
+        }
+    }
+
+    /// Starts a new Span, unless the session is already full.
+    pub fn start<S: Into<StrCow>>(&mut self, name: S) {
+        let now_ns = ns_since_epoch(self.epoch);
+        if let Some(event) = self.cursor.start(self.len, self.len == N, name.into(), now_ns) {
+            self.events[self.len] = MaybeUninit::new(event);
+            self.len += 1;
+        }
+    }
+
+    /// Ends the current Span and returns the number
+    /// of nanoseconds that passed.
+    ///
+    /// Returns 0 if the span was dropped because the session was full.
+    pub fn end<S: Into<StrCow>>(&mut self, name: S) -> u64 {
+        let now_ns = ns_since_epoch(self.epoch);
+        // The first `len` events are always initialized.
+        let events = unsafe { &mut *(&mut self.events[.. self.len] as *mut [MaybeUninit<Event>] as *mut [Event]) };
+        self.cursor.end("FixedSession", events, name.into(), now_ns)
+    }
+
+    /// The number of spans that have been recorded.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the recorded spans.
+    pub fn spans(&self) -> Vec<Span> {
+        convert_events_to_span(self.initialized().iter())
+    }
+
+    fn initialized(&self) -> &[Event] {
+        // The first `len` events are always initialized.
+        unsafe { &*(&self.events[.. self.len] as *const [MaybeUninit<Event>] as *const [Event]) }
+    }
+}
+
+impl<const N: usize> Default for FixedSession<N> {
+    fn default() -> FixedSession<N> {
+        FixedSession::new()
+    }
+}
+
+impl<const N: usize> Drop for FixedSession<N> {
+    fn drop(&mut self) {
+        for event in &mut self.events[.. self.len] {
+            unsafe { event.as_mut_ptr().drop_in_place(); }
+        }
+    }
+}
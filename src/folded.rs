@@ -0,0 +1,92 @@
+use std::io::{Result as IoResult, Write};
+use indexmap::IndexMap;
+use super::{collapse_below_depth, threads, Span, Thread};
+
+/// Options for `dump_folded_with_options`.
+#[derive(Debug, Clone)]
+pub struct FoldedOptions {
+    /// Put between the frames of a stack; `;` by default
+    pub separator: String,
+    /// Start every stack with the name of its thread, so that
+    /// `flamegraph.pl` shows each thread separately
+    pub thread_prefix: bool,
+    /// Collapse every span that is this deep into a single frame
+    /// (see `collapse_below_depth`)
+    pub collapse_below_depth: Option<u16>,
+}
+
+impl Default for FoldedOptions {
+    fn default() -> FoldedOptions {
+        FoldedOptions {
+            separator: ";".into(),
+            thread_prefix: false,
+            collapse_below_depth: None,
+        }
+    }
+}
+
+/// Writes every thread's spans in the folded-stack format that
+/// `flamegraph.pl` and similar tools read.
+///
+/// Each line is a stack of span names, outermost first, followed by
+/// the number of nanoseconds spent in the innermost span itself (not
+/// in its children).  Stacks that occur more than once are summed.
+/// Any separators or line breaks in span names are replaced with `_`
+/// so that they can't be mistaken for the boundaries of a frame.
+pub fn dump_folded<W: Write>(mut out: W) -> IoResult<()> {
+    dump_folded_to_writer(&mut out)
+}
+
+/// Like `dump_folded`, but borrows `out`, so that more can be written
+/// to it afterwards.
+pub fn dump_folded_to_writer<W: Write>(out: &mut W) -> IoResult<()> {
+    dump_folded_with_options(out, &FoldedOptions::default())
+}
+
+/// Like `dump_folded`, with the given options.
+pub fn dump_folded_with_options<W: Write>(out: W, options: &FoldedOptions) -> IoResult<()> {
+    write_folded(out, &threads(), options)
+}
+
+fn write_folded<W: Write>(mut out: W, threads: &[Thread], options: &FoldedOptions) -> IoResult<()> {
+    fn fold(span: &Span, stack: &mut Vec<String>, separator: &str, totals: &mut IndexMap<String, u64>) {
+        stack.push(escape(&span.name, separator));
+        *totals.entry(stack.join(separator)).or_insert(0) += span.self_ns();
+        for child in &span.children {
+            fold(child, stack, separator, totals);
+        }
+        stack.pop();
+    }
+
+    let mut totals = IndexMap::new();
+    for thread in threads {
+        let mut stack = vec![];
+        if options.thread_prefix {
+            stack.push(escape(&thread.label(), &options.separator));
+        }
+        let collapsed;
+        let spans = match options.collapse_below_depth {
+            Some(max_depth) => {
+                collapsed = collapse_below_depth(thread.spans.clone(), max_depth);
+                &collapsed
+            }
+            None => &thread.spans,
+        };
+        for span in spans {
+            fold(span, &mut stack, &options.separator, &mut totals);
+        }
+    }
+
+    for (stack, total) in totals {
+        if total > 0 {
+            writeln!(out, "{} {}", stack, total)?;
+        }
+    }
+    Ok(())
+}
+
+/// Replaces whatever in `name` would break up a frame in the output.
+fn escape(name: &str, separator: &str) -> String {
+    let name = if separator.is_empty() { name.to_owned() } else { name.replace(separator, "_") };
+    name.replace(['\n', '\r'], "_")
+}
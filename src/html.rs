@@ -1,22 +1,100 @@
 use std::io::Write;
 use std::io::Result as IoResult;
-use super::{Span};
-
-pub fn dump_html_custom<W: Write>(mut out: W, spans: &[Span]) -> IoResult<()> {
-    fn dump_spans<W: Write>(out: &mut W, span: &Span) -> IoResult<()> {
-        writeln!(out, "{{")?;
-        writeln!(out, r#"name: {:?},"#, span.name)?;
-        writeln!(out, "value: {},", span.delta)?;
-        writeln!(out, "start: {},", span.start_ns)?;
-        writeln!(out, "end: {},", span.end_ns)?;
-        writeln!(out, "children: [")?;
-        for child in &span.children {
-            dump_spans(out, child)?;
+use super::{collapse_below_depth, Span, Thread};
+
+fn dump_spans<W: Write>(out: &mut W, span: &Span, show_gaps: bool) -> IoResult<()> {
+    writeln!(out, "{{")?;
+    writeln!(out, r#"name: {:?},"#, span.name)?;
+    writeln!(out, "value: {},", span.delta)?;
+    writeln!(out, "start: {},", span.start_ns)?;
+    writeln!(out, "end: {},", span.end_ns)?;
+    if let Some(ref reason) = span.wait_reason {
+        writeln!(out, "wait: {:?},", reason)?;
+    }
+    if span.annotation {
+        writeln!(out, "annotation: true,")?;
+    }
+    writeln!(out, "children: [")?;
+    for child in &span.children {
+        dump_spans(out, child, show_gaps)?;
+        writeln!(out, ",")?;
+    }
+    if show_gaps && !span.children.is_empty() {
+        for (start, end) in gaps(span) {
+            dump_gap(out, start, end)?;
             writeln!(out, ",")?;
         }
-        writeln!(out, "],")?;
-        writeln!(out, "}}")?;
-        Ok(())
+    }
+    writeln!(out, "],")?;
+    writeln!(out, "}}")?;
+    Ok(())
+}
+
+/// The name given to the placeholder blocks that fill the time in
+/// a span that isn't covered by any of its children.
+const GAP_NAME: &str = "[uninstrumented]";
+
+fn dump_gap<W: Write>(out: &mut W, start: u64, end: u64) -> IoResult<()> {
+    writeln!(out, "{{")?;
+    writeln!(out, r#"name: {:?},"#, GAP_NAME)?;
+    writeln!(out, "value: {},", end - start)?;
+    writeln!(out, "start: {},", start)?;
+    writeln!(out, "end: {},", end)?;
+    writeln!(out, "gap: true,")?;
+    writeln!(out, "children: [],")?;
+    writeln!(out, "}}")?;
+    Ok(())
+}
+
+/// Returns the `(start, end)` intervals of `span` that aren't
+/// covered by any of its children.
+fn gaps(span: &Span) -> Vec<(u64, u64)> {
+    let mut gaps = vec![];
+    let mut cursor = span.start_ns;
+    for child in &span.children {
+        if child.start_ns > cursor {
+            gaps.push((cursor, child.start_ns));
+        }
+        cursor = cursor.max(child.end_ns);
+    }
+    if span.end_ns > cursor {
+        gaps.push((cursor, span.end_ns));
+    }
+    gaps
+}
+
+/// Options for the page that `dump_html_titled` renders, e.g. labels
+/// so that reports from different runs can be told apart.
+#[derive(Debug, Clone, Default)]
+pub struct HtmlOptions {
+    /// Used as the page's `<title>`, and shown in a header above the
+    /// graph
+    pub title: Option<String>,
+    /// Shown in the header under the title
+    pub subtitle: Option<String>,
+    /// Collapse every span that is this deep into a single block, so
+    /// that huge trees stay usable (see `collapse_below_depth`)
+    pub collapse_below_depth: Option<u16>,
+}
+
+/// Escapes `text` for use in HTML.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn dump_page<W, F>(mut out: W, options: &HtmlOptions, dump_data: F) -> IoResult<()>
+where W: Write, F: FnOnce(&mut W) -> IoResult<()> {
+    let mut head = String::new();
+    let mut header = String::new();
+    if let Some(ref title) = options.title {
+        head.push_str(&format!("<title>{}</title>", escape(title)));
+        header.push_str(&format!("<h1>{}</h1>", escape(title)));
+    }
+    if let Some(ref subtitle) = options.subtitle {
+        header.push_str(&format!("<h2>{}</h2>", escape(subtitle)));
+    }
+    if !header.is_empty() {
+        header = format!("<header>{}</header>", header);
     }
 
     write!(out, r#"
@@ -24,6 +102,7 @@ pub fn dump_html_custom<W: Write>(mut out: W, spans: &[Span]) -> IoResult<()> {
 <html>
     <head>
         <meta charset="utf-8">
+        {}
         <style>
             html, body {{
                 width: 100%;
@@ -40,6 +119,7 @@ pub fn dump_html_custom<W: Write>(mut out: W, spans: &[Span]) -> IoResult<()> {
         </script>
     </head>
     <body>
+        {}
         <script>
             var width = document.body.offsetWidth;
             var height = document.body.offsetHeight - 100;
@@ -57,13 +137,32 @@ pub fn dump_html_custom<W: Write>(mut out: W, spans: &[Span]) -> IoResult<()> {
                         return 0;
                     }}
                   }});
+
+            // Waits (whose names already say what they waited on) and
+            // annotations get their own colors, and annotations say so
+            // in their label.
+            var defaultLabel = flamegraph.label();
+            var defaultColor = flamegraph.color();
+            flamegraph
+                  .label(function(d) {{
+                    var label = defaultLabel(d);
+                    if (d.annotation) {{
+                        label += " [annotation]";
+                    }}
+                    return label;
+                  }})
+                  .color(function(d) {{
+                    if (!d.highlight && d.wait) {{
+                        return '#9DB4D8';
+                    }} else if (!d.highlight && d.annotation) {{
+                        return '#D8D8D8';
+                    }}
+                    return defaultColor(d);
+                  }});
             d3.select("body").datum({{ children: [
-"#, include_str!("../resources/flameGraph.css"), include_str!("../resources/d3.js"), include_str!("../resources/d3-tip.js"), include_str!("../resources/flameGraph.js"))?;
+"#, head, include_str!("../resources/flameGraph.css"), include_str!("../resources/d3.js"), include_str!("../resources/d3-tip.js"), include_str!("../resources/flameGraph.js"), header)?;
 
-    for span in spans {
-        dump_spans(&mut out, &span)?;
-        writeln!(out, ",")?;
-    }
+    dump_data(&mut out)?;
 
     write!(out, r#"]}}).call(flamegraph);
          </script>
@@ -73,6 +172,75 @@ pub fn dump_html_custom<W: Write>(mut out: W, spans: &[Span]) -> IoResult<()> {
     Ok(())
 }
 
+pub fn dump_html_custom<W: Write>(out: W, spans: &[Span]) -> IoResult<()> {
+    dump_html_titled(out, spans, &HtmlOptions::default())
+}
+
+/// Like `dump_html_custom`, but with the title and subtitle in
+/// `options` on the page, and the rest of `options` applied.
+pub fn dump_html_titled<W: Write>(out: W, spans: &[Span], options: &HtmlOptions) -> IoResult<()> {
+    let collapsed;
+    let spans = match options.collapse_below_depth {
+        Some(max_depth) => {
+            collapsed = collapse_below_depth(spans.to_vec(), max_depth);
+            &collapsed[..]
+        }
+        None => spans,
+    };
+
+    dump_page(out, options, |out| {
+        for span in spans {
+            dump_spans(out, span, false)?;
+            writeln!(out, ",")?;
+        }
+        Ok(())
+    })
+}
+
+/// Renders a flamegraph with one top-level block per thread.
+///
+/// Unlike `dump_html`, this doesn't look at what is currently being
+/// recorded, so it can be used to render data from `threads()` or
+/// from `read_threads_json`.
+pub fn dump_html_from_threads<W: Write>(out: W, threads: &[Thread]) -> IoResult<()> {
+    dump_page(out, &HtmlOptions::default(), |out| {
+        for thread in threads {
+            let name = thread.label();
+            let value: u64 = thread.spans.iter().map(|s| s.delta).sum();
+            let start = thread.spans.iter().map(|s| s.start_ns).min().unwrap_or(0);
+            let end = thread.spans.iter().map(|s| s.end_ns).max().unwrap_or(0);
+
+            writeln!(out, "{{")?;
+            writeln!(out, r#"name: {:?},"#, name)?;
+            writeln!(out, "value: {},", value)?;
+            writeln!(out, "start: {},", start)?;
+            writeln!(out, "end: {},", end)?;
+            writeln!(out, "children: [")?;
+            for span in &thread.spans {
+                dump_spans(out, span, false)?;
+                writeln!(out, ",")?;
+            }
+            writeln!(out, "],")?;
+            writeln!(out, "}},")?;
+        }
+        Ok(())
+    })
+}
+
 pub fn dump_html<W: Write>(out: W) -> IoResult<()> {
     dump_html_custom(out, &::spans())
 }
+
+/// Like `dump_html`, but the time in each span that isn't covered by
+/// any of its children is drawn as explicit "[uninstrumented]" blocks
+/// instead of being left empty.
+pub fn dump_html_show_gaps<W: Write>(out: W) -> IoResult<()> {
+    let spans = ::spans();
+    dump_page(out, &HtmlOptions::default(), |out| {
+        for span in &spans {
+            dump_spans(out, span, true)?;
+            writeln!(out, ",")?;
+        }
+        Ok(())
+    })
+}
@@ -1,4 +1,5 @@
 #![allow(unused)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 //! Here's an example of how to use some of FLAMEs APIs:
 //!
@@ -21,12 +22,13 @@
 //!         let _guard = flame::start_guard("cpu-heavy calculation");
 //!         cpu_heavy_operations_1();
 //!         // Notes can be used to annotate a particular instant in time.
-//!         flame::note("something interesting happened", None);
+//!         flame::note("something interesting happened", None::<&str>);
 //!         cpu_heavy_operations_2()
 //!     };
 //!
 //!     // Dump the report to disk
-//!     flame::dump_html(&mut File::create("flame-graph.html").unwrap()).unwrap();
+//!     let path = ::std::env::temp_dir().join("flame-graph.html");
+//!     flame::dump_html(&mut File::create(path).unwrap()).unwrap();
 //!
 //!     // Or read and process the data yourself!
 //!     let spans = flame::spans();
@@ -41,9 +43,17 @@
 //! ```
 
 
+#[cfg_attr(not(feature = "std"), macro_use)]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate core;
+#[cfg(feature = "std")]
 #[macro_use]
 extern crate lazy_static;
+#[cfg(feature = "std")]
 extern crate thread_id;
+#[cfg(feature = "std")]
+extern crate indexmap;
 
 #[cfg(feature = "json")]
 #[macro_use]
@@ -52,19 +62,91 @@ extern crate serde_derive;
 extern crate serde;
 #[cfg(feature = "json")]
 extern crate serde_json;
+#[cfg(feature = "async-tokio")]
+extern crate tokio;
+#[cfg(feature = "digest")]
+extern crate sha2;
 
-mod html;
+/// Declares items that need `std`.
+///
+/// Without the `std` feature (which is on by default), these are left
+/// out, and the crate is `#![no_std]`.  All that's left then is the
+/// data model in `event` and the `Collector` that records into it.
+macro_rules! std_only {
+    ($($item:item)*) => {
+        $(
+            #[cfg(feature = "std")]
+            $item
+        )*
+    }
+}
+
+mod event;
+mod collector;
+
+pub use event::{AttrValue, Note, NoteLevel, Span, StrCow};
+pub use collector::Collector;
+use event::{convert_events_to_span, ns_to_stamp, stamp_to_ns, Event, Stamp};
+
+std_only! {
 
-use std::cell::{RefCell, Cell};
-use std::iter::Peekable;
+mod html;
+pub mod checked;
+mod transform;
+mod fixed;
+mod folded;
+mod dot;
+mod csv;
+mod stats;
+#[cfg(feature = "json")]
+pub mod chrome_trace;
+#[cfg(feature = "digest")]
+mod canonical;
+#[cfg(feature = "async-tokio")]
+mod async_tokio;
+#[cfg(feature = "skew-check")]
+mod skew;
+#[cfg(feature = "otel")]
+mod otel;
+#[cfg(feature = "testutil")]
+mod testutil;
+#[cfg(feature = "rss")]
+mod rss;
+
+use std::cell::{RefCell, Cell, OnceCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use indexmap::IndexMap;
 use std::borrow::Cow;
-use std::sync::Mutex;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, TryLockError};
+use std::thread::JoinHandle;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 use std::io::{Write, Error as IoError};
 
-pub type StrCow = Cow<'static, str>;
+type CommitSink = Arc<dyn Fn(&Thread) + Send + Sync>;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static AGGREGATE_ONLY: AtomicBool = AtomicBool::new(false);
+static RING_BUFFER_CAPACITY: AtomicUsize = AtomicUsize::new(0);
+static MAX_EVENTS: AtomicUsize = AtomicUsize::new(usize::MAX);
+static DROPPED_SPANS: AtomicU64 = AtomicU64::new(0);
+static MAX_NOTE_LEN: AtomicUsize = AtomicUsize::new(usize::MAX);
+static COMMIT_ON_PANIC: AtomicBool = AtomicBool::new(false);
+static CLOSE_ON_COMMIT: AtomicBool = AtomicBool::new(false);
+static SAMPLE_RATE: AtomicU32 = AtomicU32::new(1);
+static GLOBAL_MODE: AtomicBool = AtomicBool::new(false);
+/// True while a clock has been installed with `set_clock`, so that the
+/// default clock doesn't have to take the lock
+static CUSTOM_CLOCK: AtomicBool = AtomicBool::new(false);
 
 lazy_static!(static ref ALL_THREADS: Mutex<Vec<(usize, Option<String>, PrivateFrame)>> = Mutex::new(Vec::new()););
+lazy_static!(static ref REGISTERED_THREADS: Mutex<Vec<(usize, Option<String>)>> = Mutex::new(Vec::new()););
+lazy_static!(static ref PROCESS_EPOCH: Instant = Instant::now(););
+lazy_static!(static ref COMMIT_SINK: Mutex<Option<CommitSink>> = Mutex::new(None););
+lazy_static!(static ref CLOCK: RwLock<Option<Box<dyn Clock + Send + Sync>>> = RwLock::new(None););
+// When both are held, this is always locked before `ALL_THREADS`.
+lazy_static!(static ref GLOBAL_LIBRARY: Mutex<Library> = Mutex::new(Library::new()););
 thread_local!(static LIBRARY: RefCell<Library> = RefCell::new(Library::new()));
 
 #[derive(Debug)]
@@ -72,170 +154,310 @@ struct Library {
     name: Option<String>,
     current: PrivateFrame,
     epoch: Instant,
+    /// True while running inside of `suppressed`
+    suppressed: bool,
+    /// The number of running spans that were started while suppressed
+    suppressed_running: u32,
+    /// The number of running spans that weren't recorded because the
+    /// thread was over `set_max_events`
+    dropped_running: u32,
+    /// Put in front of the names of spans, set by `with_prefix`
+    prefix: Option<String>,
+    /// Where the thread was spawned, set by `spawn_named`
+    spawned_at: Option<String>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct PrivateFrame {
     next_id: u32,
     all: Vec<Event>,
     id_stack: Vec<u32>,
+    /// Spans recorded while in aggregate-only mode, by name
+    aggregates: IndexMap<StrCow, Aggregate>,
+    /// The names and start times of the spans that are running in
+    /// aggregate-only mode
+    aggregate_stack: Vec<(StrCow, u64)>,
+    /// The `seq` of the next note
+    next_note_seq: u64,
+    /// The thread's `epoch_offset_ns`, set when the frame is committed
+    epoch_offset_ns: u64,
+    /// The thread's `spawned_at`, set when the frame is committed
+    spawned_at: Option<String>,
+    /// Completed top-level spans, when recording into a ring buffer
+    ring: VecDeque<Span>,
+    /// The number of top-level spans that were started, for sampling
+    sample_counter: u64,
+    /// The number of running spans that are being skipped because
+    /// their top-level span wasn't sampled
+    skip_depth: u32,
+    /// The wall clock time that running spans started at, by id
+    #[cfg(feature = "skew-check")]
+    wall_starts: HashMap<u32, ::std::time::SystemTime>,
 }
 
-#[derive(Debug)]
-struct Event {
-    id: u32,
-    parent: Option<u32>,
-    name: StrCow,
-    collapse: bool,
-    start_ns: u64,
-    end_ns: Option<u64>,
-    delta: Option<u64>,
-    notes: Vec<Note>,
-}
-
-/// A named timespan.
-///
-/// The span is the most important feature of Flame.  It denotes
-/// a chunk of time that is important to you.
-///
-/// The Span records
-/// * Start and stop time
-/// * A list of children (also called sub-spans)
-/// * A list of notes
-#[derive(Debug, Clone)]
-#[cfg_attr(feature = "json", derive(Serialize))]
-pub struct Span {
-    /// The name of the span
-    pub name: StrCow,
-    /// The timestamp of the start of the span
-    pub start_ns: u64,
-    /// The timestamp of the end of the span
-    pub end_ns: u64,
-    /// The time that ellapsed between start_ns and end_ns
-    pub delta: u64,
-    /// How deep this span is in the tree
-    pub depth: u16,
-    /// A list of spans that occurred inside this one
-    pub children: Vec<Span>,
-    /// A list of notes that occurred inside this span
-    pub notes: Vec<Note>,
-    #[cfg_attr(feature = "json", serde(skip_serializing))]
-    collapsable: bool,
-    #[cfg_attr(feature = "json", serde(skip_serializing))]
-    _priv: (),
-}
-
-/// A note for use in debugging.
 #[derive(Debug, Clone)]
-#[cfg_attr(feature = "json", derive(Serialize))]
-pub struct Note {
-    /// A short name describing what happened at some instant in time
-    pub name: StrCow,
-    /// A longer description
-    pub description: Option<StrCow>,
-    /// The time that the note was added
-    pub instant: u64,
-    #[cfg_attr(feature = "json", serde(skip_serializing))]
-    _priv: (),
+struct Aggregate {
+    count: u64,
+    total_ns: u64,
+    first_start_ns: u64,
+    last_end_ns: u64,
 }
 
 /// A collection of events that happened on a single thread.
 #[derive(Debug, Clone)]
-#[cfg_attr(feature = "json", derive(Serialize))]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+#[allow(clippy::manual_non_exhaustive)]
 pub struct Thread {
     pub id: usize,
     pub name: Option<String>,
     pub spans: Vec<Span>,
-    #[cfg_attr(feature = "json", serde(skip_serializing))]
+    /// The time between the very first epoch in the process and the
+    /// epoch that this thread's timestamps are relative to.
+    ///
+    /// Every thread measures time from its own epoch; adding this
+    /// offset puts all threads on the same timeline (see `align_threads`).
+    #[cfg_attr(feature = "json", serde(default))]
+    pub epoch_offset_ns: u64,
+    /// Where the thread was spawned, as "file:line:column", if it
+    /// was spawned with `spawn_named`
+    #[cfg_attr(feature = "json", serde(default))]
+    pub spawned_at: Option<String>,
+    #[cfg_attr(feature = "json", serde(skip))]
     _priv: (),
 }
 
+/// The ways in which a Flame operation can fail.
+///
+/// The functions at the root of the crate panic when they fail;
+/// the ones in the `checked` module return this instead.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FlameError {
+    /// A span was ended, or a note was recorded, while no span
+    /// was running.  `name` is the name that was passed in.
+    NoActiveSpan { name: StrCow },
+    /// A span was ended with a name that differs from the
+    /// name of the span that is currently running.
+    NameMismatch {
+        /// The name of the span that is currently running
+        expected: StrCow,
+        /// The name that was passed in
+        found: StrCow,
+        /// The name of a running span that is close to `found`,
+        /// in case it was misspelled
+        suggestion: Option<StrCow>,
+    },
+    /// Nothing was recorded because recording is turned off.
+    /// See `set_enabled`.
+    Disabled,
+}
+
+impl fmt::Display for FlameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FlameError::NoActiveSpan { ref name } =>
+                write!(f, "{:?} was used without a currently running span", name),
+            FlameError::NameMismatch { ref expected, ref found, ref suggestion } => {
+                write!(f, "attempted to end {:?} while {:?} was running", found, expected)?;
+                if let Some(ref suggestion) = *suggestion {
+                    write!(f, "; did you mean {:?}?", suggestion)?;
+                }
+                Ok(())
+            }
+            FlameError::Disabled => write!(f, "recording is disabled"),
+        }
+    }
+}
+
+impl ::std::error::Error for FlameError {}
+
+/// The spans that were running at some moment, innermost first,
+/// along with how many nanoseconds each of them had been running for.
+///
+/// Returned by `current_context`, and meant to be included in error
+/// reports to show what the program was doing when the error happened.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ProfileContext {
+    pub spans: Vec<(StrCow, u64)>,
+}
+
+impl fmt::Display for ProfileContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.spans.is_empty() {
+            return write!(f, "no spans running");
+        }
+        for (i, &(ref name, elapsed)) in self.spans.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "in {} (running for {}ms)", name, elapsed as f32 / 1000000.0)?;
+        }
+        Ok(())
+    }
+}
+
 #[must_use = "The guard is immediately dropped after instantiation. This is probably not
 what you want! Consider using a `let` binding to increase its lifetime."]
 pub struct SpanGuard {
     name: Option<StrCow>,
+    /// The id of the span, if it was recorded as an event
+    id: Option<u32>,
     collapse: bool,
 }
 
 impl Drop for SpanGuard {
     fn drop(&mut self) {
-        if ::std::thread::panicking() { return; }
-        let name = self.name.take().unwrap();
-        end_impl(name, self.collapse);
+        self.finish();
     }
 }
 
+/// Checks whether the span `id` that a guard was created for has
+/// already been ended, e.g. by an explicit call to `end`.  If it was,
+/// a `Warn` note saying so is recorded on it, since ending it again
+/// would end its parent instead.
+fn already_ended(id: u32, name: &StrCow) -> bool {
+    with_library(|library| {
+        let name = library.qualify(name.clone());
+        let instant = ns_since_epoch(library.epoch);
+        let collector = &mut library.current;
+        let seq = collector.next_note_seq;
+        match collector.find_event_mut(id) {
+            Some(event) if event.name == name && event.end.is_some() => {
+                event.notes.push(Note {
+                    name: "ended twice".into(),
+                    description: Some(format!("{:?} was ended before its guard was dropped", name).into()),
+                    instant,
+                    seq,
+                    level: NoteLevel::Warn,
+                    track: None,
+                    _priv: (),
+                });
+                collector.next_note_seq += 1;
+                true
+            }
+            Some(_) => false,
+            // It ended and was taken out of the events, e.g. into the ring buffer
+            None => true,
+        }
+    })
+}
+
 impl SpanGuard {
-    pub fn end(self) { }
-    pub fn end_collapse(mut self) {
+    /// Ends the span, and returns how long it ran for in nanoseconds,
+    /// like `end`.
+    pub fn end(mut self) -> u64 {
+        self.finish()
+    }
+
+    /// Ends the span, collapsing it into the previous one if they
+    /// match, like `end_collapse`.
+    pub fn end_collapse(mut self) -> u64 {
         self.collapse = true;
+        self.finish()
+    }
+
+    /// Ends the span unless it was already ended, returning its delta.
+    fn finish(&mut self) -> u64 {
+        let name = match self.name.take() {
+            Some(name) => name,
+            None => return 0,
+        };
+        if let Some(id) = self.id {
+            if already_ended(id, &name) {
+                return 0;
+            }
+        }
+        if ::std::thread::panicking() {
+            // Still close the span, so that it shows up in any report
+            // made after the panic is caught, but don't risk a second
+            // panic if it can't be ended.
+            return try_end_impl(name, self.collapse).unwrap_or(0);
+        }
+        end_impl(name, self.collapse)
     }
 }
 
-fn ns_since_epoch(epoch: Instant) -> u64 {
-    let elapsed = epoch.elapsed();
-    elapsed.as_secs() * 1000_000_000 + u64::from(elapsed.subsec_nanos())
+/// A span that checks that a series of steps happen in the expected
+/// order.  Created by `start_sequence`.
+///
+/// Each call to `step` records a note, and if the step isn't the one
+/// that was expected next, an `Error` note describing the mismatch
+/// is recorded too.  When the guard is dropped, an `Error` note is
+/// recorded for every step that was never reached, and the span ends.
+#[must_use = "The guard is immediately dropped after instantiation. This is probably not
+what you want! Consider using a `let` binding to increase its lifetime."]
+pub struct SequenceGuard {
+    expected: Vec<String>,
+    next: usize,
+    _span: SpanGuard,
 }
 
-fn convert_events_to_span<'a, I>(events: I) -> Vec<Span>
-where I: Iterator<Item = &'a Event> {
-    let mut iterator = events.peekable();
-    let mut v = vec![];
-    while let Some(event) = iterator.next() {
-        if let Some(span) = event_to_span(event, &mut iterator, 0) {
-            v.push(span);
-        }
+/// Starts a span that expects the steps in `expected` to happen
+/// in order.  See `SequenceGuard`.
+pub fn start_sequence<S: Into<StrCow>>(name: S, expected: &[&str]) -> SequenceGuard {
+    SequenceGuard {
+        expected: expected.iter().map(|step| step.to_string()).collect(),
+        next: 0,
+        _span: start_guard(name),
     }
-    v
 }
 
-fn event_to_span<'a, I: Iterator<Item = &'a Event>>(event: &Event, events: &mut Peekable<I>, depth: u16) -> Option<Span> {
-    if event.end_ns.is_some() && event.delta.is_some() {
-        let mut span = Span {
-            name: event.name.clone(),
-            start_ns: event.start_ns,
-            end_ns: event.end_ns.unwrap(),
-            delta: event.delta.unwrap(),
-            depth,
-            children: vec![],
-            notes: event.notes.clone(),
-            collapsable: event.collapse,
-            _priv: ()
+impl SequenceGuard {
+    /// Records that the step `name` happened.
+    pub fn step<S: Into<StrCow>>(&mut self, name: S) {
+        let name = name.into();
+        let mismatch = match self.expected.get(self.next) {
+            Some(expected) if *expected == name => None,
+            Some(expected) => Some(format!("expected {:?}, got {:?}", expected, name)),
+            None => Some(format!("expected no more steps, got {:?}", name)),
         };
+        self.next += 1;
 
-        loop {
-            {
-                match events.peek() {
-                    Some(next) if next.parent != Some(event.id) => break,
-                    None => break,
-                    _ => {}
-                }
-            }
+        note_impl(name, None, None, NoteLevel::Info, false);
+        if let Some(mismatch) = mismatch {
+            note_impl("unexpected step".into(), Some(mismatch.into()), None, NoteLevel::Error, false);
+        }
+    }
+}
 
-            let next = events.next().unwrap();
-            let child = event_to_span(next, events, depth + 1);
-            if let Some(child) = child {
-                // Try to collapse with the previous span
-                if !span.children.is_empty() && child.collapsable && child.children.is_empty() {
-                    let last = span.children.last_mut().unwrap();
-                    if last.name == child.name && last.depth == child.depth {
-                        last.end_ns = child.end_ns;
-                        last.delta += child.delta;
-                        continue;
-                    }
-                }
+impl Drop for SequenceGuard {
+    fn drop(&mut self) {
+        if ::std::thread::panicking() { return; }
+        for step in self.expected.iter().skip(self.next) {
+            note_impl("unreached step".into(), Some(step.clone().into()), None, NoteLevel::Error, false);
+        }
+    }
+}
 
-                // Otherwise, it's a new node
-                span.children.push(child);
-            }
+fn duration_to_ns(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + u64::from(duration.subsec_nanos())
+}
+
+fn epoch_offset_ns(epoch: Instant) -> u64 {
+    duration_to_ns(epoch.saturating_duration_since(*PROCESS_EPOCH))
+}
+
+fn ns_since_epoch(epoch: Instant) -> u64 {
+    if CUSTOM_CLOCK.load(Ordering::Relaxed) {
+        if let Some(ref clock) = *CLOCK.read().unwrap_or_else(|e| e.into_inner()) {
+            return clock.now_ns();
         }
-        Some(span)
-    } else {
-        None
     }
+    duration_to_ns(epoch.elapsed())
 }
 
 impl Span {
+    /// Returns the notes on this span grouped by their `track`, in the
+    /// order that each track was first used in.  Notes that aren't on
+    /// a track are grouped under `None`.  Within a track, notes are in
+    /// the order that they were recorded in.
+    pub fn notes_by_track(&self) -> Vec<(Option<StrCow>, Vec<&Note>)> {
+        let mut tracks: IndexMap<Option<StrCow>, Vec<&Note>> = IndexMap::new();
+        for note in self.notes_sorted() {
+            tracks.entry(note.track.clone()).or_default().push(note);
+        }
+        tracks.into_iter().collect()
+    }
+
     #[cfg(feature = "json")]
     pub fn into_json(&self) -> String {
         ::serde_json::to_string_pretty(self).unwrap()
@@ -243,6 +465,14 @@ impl Span {
 }
 
 impl Thread {
+    /// A human readable name for the thread, used by the exporters.
+    fn label(&self) -> String {
+        match self.name {
+            Some(ref name) => format!("{} ({})", name, self.id),
+            None => format!("thread {}", self.id),
+        }
+    }
+
     #[cfg(feature = "json")]
     pub fn into_json(&self) -> String {
         ::serde_json::to_string_pretty(self).unwrap()
@@ -254,151 +484,1602 @@ impl Thread {
     }
 }
 
+impl PrivateFrame {
+    fn new() -> PrivateFrame {
+        PrivateFrame {
+            all: vec![],
+            id_stack: vec![],
+            next_id: 0,
+            aggregates: IndexMap::new(),
+            aggregate_stack: vec![],
+            next_note_seq: 0,
+            epoch_offset_ns: 0,
+            spawned_at: None,
+            ring: VecDeque::new(),
+            sample_counter: 0,
+            skip_depth: 0,
+            #[cfg(feature = "skew-check")]
+            wall_starts: HashMap::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.all.is_empty() && self.aggregates.is_empty() && self.ring.is_empty()
+    }
+
+    /// Finds where the event `id` is in `all`.
+    ///
+    /// Ids keep increasing, and `all` stays in order of id, but
+    /// starts at the id of its first event once earlier ones have
+    /// been moved into the ring buffer, and can have gaps where that
+    /// happened to events in the middle.
+    fn position(&self, id: u32) -> Option<usize> {
+        let base = self.all.first()?.id;
+        let guess = id.checked_sub(base)? as usize;
+        match self.all.get(guess) {
+            Some(event) if event.id == id => Some(guess),
+            _ => self.all.binary_search_by_key(&id, |event| event.id).ok(),
+        }
+    }
+
+    fn find_event(&self, id: u32) -> Option<&Event> {
+        self.position(id).map(|position| &self.all[position])
+    }
+
+    fn find_event_mut(&mut self, id: u32) -> Option<&mut Event> {
+        let position = self.position(id)?;
+        Some(&mut self.all[position])
+    }
+
+    /// The event `id`, which must still be in `all`, e.g. because it's
+    /// running.
+    fn event(&self, id: u32) -> &Event {
+        self.find_event(id).expect("flame: a running span is missing its event")
+    }
+
+    fn event_mut(&mut self, id: u32) -> &mut Event {
+        self.find_event_mut(id).expect("flame: a running span is missing its event")
+    }
+
+    /// The positions in `all` of the event `id` and of everything that
+    /// was recorded inside of it, or `None` if any of them is still
+    /// running.
+    fn finished_subtree(&self, id: u32) -> Option<Vec<usize>> {
+        let first = self.position(id)?;
+        // Children always come after their parents, so one pass finds
+        // them all.
+        let mut ids = vec![id];
+        let mut positions = vec![first];
+        for (position, event) in self.all.iter().enumerate().skip(first + 1) {
+            if event.parent.is_some_and(|parent| ids.binary_search(&parent).is_ok()) {
+                ids.push(event.id);
+                positions.push(position);
+            }
+        }
+        if positions.iter().any(|&position| self.all[position].ended_at().is_none()) {
+            return None;
+        }
+        Some(positions)
+    }
+
+    /// Removes the events at `positions`, which must be in order.
+    fn remove_events(&mut self, positions: &[usize]) {
+        let mut position = 0;
+        let mut remove = positions.iter().peekable();
+        self.all.retain(|_| {
+            let keep = remove.peek() != Some(&&position);
+            if !keep {
+                remove.next();
+            }
+            position += 1;
+            keep
+        });
+    }
+
+    /// Gives back any memory that the frame's buffers are holding on
+    /// to but not using.
+    fn shrink_to_fit(&mut self) {
+        self.all.shrink_to_fit();
+        self.id_stack.shrink_to_fit();
+        self.aggregate_stack.shrink_to_fit();
+        self.ring.shrink_to_fit();
+    }
+
+    /// Roughly how many bytes the frame's events take up, including the
+    /// names and notes that they own.  See `approx_memory_bytes`.
+    fn approx_memory_bytes(&self) -> usize {
+        fn owned(text: &StrCow) -> usize {
+            match *text {
+                Cow::Owned(ref text) => text.capacity(),
+                Cow::Borrowed(_) => 0,
+            }
+        }
+
+        let events = self.all.iter().map(|event| {
+            let notes = event.notes.iter()
+                .map(|note| owned(&note.name) + note.description.as_ref().map_or(0, owned))
+                .sum::<usize>();
+            owned(&event.name) + event.notes.capacity() * ::std::mem::size_of::<Note>() + notes
+        }).sum::<usize>();
+        self.all.capacity() * ::std::mem::size_of::<Event>()
+            + self.ring.capacity() * ::std::mem::size_of::<Span>()
+            + events
+    }
+
+    /// Converts everything recorded in this frame into spans.
+    fn spans(&self) -> Vec<Span> {
+        let mut spans: Vec<Span> = self.ring.iter().cloned().collect();
+        spans.extend(convert_events_to_span(self.all.iter()));
+        for (name, aggregate) in &self.aggregates {
+            spans.push(Span {
+                name: name.clone(),
+                start_ns: aggregate.first_start_ns,
+                end_ns: aggregate.last_end_ns,
+                delta: aggregate.total_ns,
+                depth: 0,
+                children: vec![],
+                notes: vec![],
+                attributes: vec![],
+                metrics: BTreeMap::new(),
+                collapse_count: aggregate.count,
+                wait_reason: None,
+                annotation: false,
+                #[cfg(feature = "rss")]
+                rss: Default::default(),
+                inclusive_ns: None,
+                exclusive_ns: None,
+                collapsable: true,
+                _priv: (),
+            });
+        }
+        spans
+    }
+}
+
 impl Library {
     fn new() -> Library {
+        // Make sure the process epoch comes before every thread's
+        lazy_static::initialize(&PROCESS_EPOCH);
         Library {
             name: ::std::thread::current().name().map(Into::into),
-            current: PrivateFrame {
-                all: vec![],
-                id_stack: vec![],
-                next_id: 0,
-            },
+            current: PrivateFrame::new(),
             epoch: Instant::now(),
+            suppressed: false,
+            suppressed_running: 0,
+            dropped_running: 0,
+            prefix: None,
+            spawned_at: None,
+        }
+    }
+
+    /// Puts the current prefix (if any) in front of `name`.
+    fn qualify(&self, name: StrCow) -> StrCow {
+        match self.prefix {
+            Some(ref prefix) => format!("{}::{}", prefix, name).into(),
+            None => name,
+        }
+    }
+
+    /// Checks whether a span that's being started shouldn't be
+    /// recorded, because it's inside of `suppressed`, wasn't sampled
+    /// (see `set_sample_rate`) or the thread has recorded as many
+    /// events as `set_max_events` allows, and keeps count of it if so,
+    /// so that its end can be skipped as well.
+    fn skip_start(&mut self) -> bool {
+        if self.suppressed {
+            self.suppressed_running += 1;
+            return true;
+        }
+
+        let collector = &mut self.current;
+        if collector.skip_depth > 0 {
+            collector.skip_depth += 1;
+            return true;
+        }
+        if self.dropped_running == 0 && collector.id_stack.is_empty() && collector.aggregate_stack.is_empty() {
+            let rate = u64::from(SAMPLE_RATE.load(Ordering::Relaxed).max(1));
+            let sampled = collector.sample_counter.is_multiple_of(rate);
+            collector.sample_counter += 1;
+            if !sampled {
+                collector.skip_depth = 1;
+                return true;
+            }
+        }
+        if self.dropped_running > 0
+            || (!is_aggregate_only() && collector.all.len() >= MAX_EVENTS.load(Ordering::Relaxed)) {
+            self.dropped_running += 1;
+            DROPPED_SPANS.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        false
+    }
+
+    /// Checks whether the span that's being ended was skipped by
+    /// `skip_start`.
+    fn skip_end(&mut self) -> bool {
+        if self.suppressed_running > 0 {
+            self.suppressed_running -= 1;
+            return true;
+        }
+        if self.dropped_running > 0 {
+            self.dropped_running -= 1;
+            return true;
+        }
+        if self.current.skip_depth > 0 {
+            self.current.skip_depth -= 1;
+            return true;
+        }
+        false
+    }
+
+    /// True if notes and the like shouldn't be recorded right now.
+    fn skipping(&self) -> bool {
+        self.suppressed || self.dropped_running > 0 || self.current.skip_depth > 0
+    }
+}
+
+/// Runs `f` with the `Library` that the caller should be recording into.
+///
+/// This is the thread's own `Library`, unless the caller is running
+/// inside of a future wrapped by `with_task_profiling`, in which case
+/// the task's `Library` is used instead.
+///
+/// Once the thread's `Library` has been destroyed, which can happen
+/// when Flame is used from another thread-local's destructor while the
+/// thread exits, `f` isn't run and `R::default()` is returned instead.
+fn with_library<F, R>(f: F) -> R
+where F: FnOnce(&mut Library) -> R, R: Default {
+    try_with_library(f).unwrap_or_default()
+}
+
+/// Like `with_library`, but returns `None` if the thread's `Library`
+/// has already been destroyed.
+fn try_with_library<F, R>(f: F) -> Option<R>
+where F: FnOnce(&mut Library) -> R {
+    if is_global_mode() {
+        return Some(f(&mut GLOBAL_LIBRARY.lock().unwrap_or_else(|e| e.into_inner())));
+    }
+
+    #[cfg(feature = "async-tokio")]
+    {
+        if async_tokio::in_task() {
+            return Some(async_tokio::TASK_LIBRARY.with(|library| f(&mut library.borrow_mut())));
         }
     }
+
+    LIBRARY.try_with(|library| f(&mut library.borrow_mut())).ok()
 }
 
 fn commit_impl(library: &mut Library) {
+    let name = library.name.clone();
+    commit_as_impl(library, name);
+}
+
+/// Commits the library's frame under `name`.
+fn commit_as_impl(library: &mut Library, name: Option<String>) {
     use std::thread;
     use std::sync::MutexGuard;
     use std::mem;
     
-    let mut frame = PrivateFrame {
-        all: vec![],
-        id_stack: vec![],
-        next_id: 0,
-    };
+    if CLOSE_ON_COMMIT.load(Ordering::SeqCst) {
+        close_unclosed(library);
+    }
+
+    let mut frame = PrivateFrame::new();
 
     mem::swap(&mut frame, &mut library.current);
-    if frame.all.is_empty() {
+    if frame.is_empty() {
+        register_impl(::thread_id::get(), name);
         return;
     }
+    frame.epoch_offset_ns = epoch_offset_ns(library.epoch);
+    frame.spawned_at = library.spawned_at.clone();
+
+    let sink = COMMIT_SINK.lock().ok().and_then(|sink| sink.clone());
+    if let Some(sink) = sink {
+        sink(&Thread {
+            id: ::thread_id::get(),
+            name: name.clone(),
+            spans: frame.spans(),
+            epoch_offset_ns: frame.epoch_offset_ns,
+            spawned_at: frame.spawned_at.clone(),
+            _priv: (),
+        });
+    }
 
     if let Ok(mut handle) = ALL_THREADS.lock() {
-        let thread_name = library.name.clone();
         let thread_id = ::thread_id::get();
-        handle.push((thread_id, thread_name, frame))
+        handle.push((thread_id, name, frame))
     }
 }
 
-pub fn commit_thread() {
-    LIBRARY.with(|library| commit_impl(&mut *library.borrow_mut()));
+/// Calls `sink` with every thread's spans as the thread commits them,
+/// replacing any sink that was installed before.
+///
+/// The sink stays installed until `reset_hooks` is called; `clear`
+/// doesn't remove it.
+pub fn set_commit_sink<F>(sink: F)
+where F: Fn(&Thread) + Send + Sync + 'static {
+    if let Ok(mut handle) = COMMIT_SINK.lock() {
+        *handle = Some(Arc::new(sink));
+    }
 }
 
-impl Drop for Library {
-    fn drop(&mut self) {
-        if ::std::thread::panicking() { return; }
-        commit_impl(self);
-    }
+/// A source of timestamps for spans and notes.  See `set_clock`.
+pub trait Clock {
+    /// The current time in nanoseconds.  This must never go backwards.
+    fn now_ns(&self) -> u64;
 }
 
-/// Starts a `Span` and also returns a `SpanGuard`.
+/// Takes every timestamp from `clock` instead of measuring the time
+/// since the thread's epoch with `Instant`, replacing any clock that
+/// was installed before.
 ///
-/// When the `SpanGuard` is dropped (or `.end()` is called on it),
-/// the span will automatically be ended.
-pub fn start_guard<S: Into<StrCow>>(name: S) -> SpanGuard {
-    let name = name.into();
-    start(name.clone());
-    SpanGuard { name: Some(name), collapse: false }
+/// The readings are used as they are, on every thread, which makes it
+/// possible to test instrumentation deterministically with a clock
+/// that only moves when told to, or to use a cheaper time source.
+/// The clock stays installed until `reset_hooks` is called.
+pub fn set_clock(clock: Box<dyn Clock + Send + Sync>) {
+    let mut handle = CLOCK.write().unwrap_or_else(|e| e.into_inner());
+    *handle = Some(clock);
+    CUSTOM_CLOCK.store(true, Ordering::SeqCst);
 }
 
-/// Starts and ends a `Span` that lasts for the duration of the
-/// function `f`.
-pub fn span_of<S, F, R>(name: S, f: F) -> R where
-S: Into<StrCow>,
-F: FnOnce() -> R
-{
-    let name = name.into();
-    start(name.clone());
-    let r = f();
-    end(name);
-    r
+/// Removes every installed hook, such as the sink set with
+/// `set_commit_sink` or the clock set with `set_clock`.
+///
+/// `clear` only throws away recorded spans, so tests that install
+/// hooks should call this as well to avoid leaking them into other
+/// tests.  There's no separate `init()` to call afterwards; hooks
+/// are simply absent until they're installed again.
+pub fn reset_hooks() {
+    if let Ok(mut handle) = COMMIT_SINK.lock() {
+        *handle = None;
+    }
+    CUSTOM_CLOCK.store(false, Ordering::SeqCst);
+    *CLOCK.write().unwrap_or_else(|e| e.into_inner()) = None;
 }
 
-/// Starts a new Span
-pub fn start<S: Into<StrCow>>(name: S) {
-    LIBRARY.with(|library| {
-        let mut library = library.borrow_mut();
-        let epoch = library.epoch;
-
-        let collector = &mut library.current;
-        let id = collector.next_id;
-        collector.next_id += 1;
-
-        let parent = collector.id_stack.last().cloned();
-
-        let this = Event {
-            id,
-            parent,
-            name: name.into(),
-            collapse: false,
-            start_ns: ns_since_epoch(epoch),
-            end_ns: None,
-            delta: None,
-            notes: vec![]
-        };
-
-        collector.all.push(this);
-        collector.id_stack.push(id);
-    });
+pub fn commit_thread() {
+    with_library(commit_impl);
 }
 
-fn end_impl<S: Into<StrCow>>(name: S, collapse: bool) -> u64 {
-    use std::thread;
-
+/// Like `commit_thread`, but the committed thread is named `name`
+/// instead of after the OS thread.  This is useful for thread pools,
+/// where the same OS thread plays different roles over time.
+pub fn commit_thread_as<S: Into<String>>(name: S) {
     let name = name.into();
-    let delta = LIBRARY.with(|library| {
-        let mut library = library.borrow_mut();
-        let epoch = library.epoch;
-        let collector = &mut library.current;
+    with_library(|library| commit_as_impl(library, Some(name)));
+}
 
-        let current_id = match collector.id_stack.pop() {
-            Some(id) => id,
-            None if thread::panicking() => 0,
-            None => panic!("flame::end({:?}) called without a currently running span!", &name)
-        };
+/// Spawns a thread named `label`, like `std::thread::Builder::spawn`,
+/// and records where it was spawned from in the thread's `spawned_at`.
+///
+/// This makes it possible to tell which call site started an otherwise
+/// anonymous worker thread when looking at a dump.
+///
+/// # Panics
+///
+/// Panics if the operating system fails to create the thread.
+#[track_caller]
+pub fn spawn_named<F, T>(label: &'static str, f: F) -> JoinHandle<T>
+where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    let location = ::std::panic::Location::caller();
+    let spawned_at = format!("{}:{}:{}", location.file(), location.line(), location.column());
+    ::std::thread::Builder::new()
+        .name(label.into())
+        .spawn(move || {
+            with_library(|library| {
+                library.name = Some(label.into());
+                library.spawned_at = Some(spawned_at);
+            });
+            f()
+        })
+        .expect("failed to spawn thread")
+}
 
-        let event = &mut collector.all[current_id as usize];
+/// Makes the current thread show up in `threads_include_empty()`,
+/// even if it never records anything.
+///
+/// Threads that commit without having recorded anything are
+/// registered automatically.
+pub fn register_thread() {
+    let name = with_library(|library| library.name.clone());
+    register_impl(::thread_id::get(), name);
+}
 
-        if event.name != name {
-            panic!("flame::end({}) attempted to end {}", &name, event.name);
+fn register_impl(id: usize, name: Option<String>) {
+    if let Ok(mut handle) = REGISTERED_THREADS.lock() {
+        if !handle.iter().any(|&(registered, _)| registered == id) {
+            handle.push((id, name));
         }
+    }
+}
 
-        let timestamp = ns_since_epoch(epoch);
-        event.end_ns = Some(timestamp);
-        event.collapse = collapse;
-        event.delta = Some(timestamp - event.start_ns);
-        event.delta
-    });
-
-    match delta {
-        Some(d) => d,
-        None => 0, // panicking
+impl Drop for Library {
+    fn drop(&mut self) {
+        let commit_on_panic = COMMIT_ON_PANIC.load(Ordering::SeqCst);
+        if !commit_on_panic && ::std::thread::panicking() { return; }
+
+        // This runs while thread locals are being torn down, where a
+        // panic (e.g. from a commit sink) would abort the process,
+        // which is a lot worse than losing the profile.
+        let library = ::std::panic::AssertUnwindSafe(self);
+        let _ = ::std::panic::catch_unwind(move || {
+            let library = library;
+            if commit_on_panic {
+                end_all_impl(library.0);
+            }
+            commit_impl(library.0);
+        });
     }
 }
 
-/// Ends the current Span and returns the number
-/// of nanoseconds that passed.
-pub fn end<S: Into<StrCow>>(name: S) -> u64 {
-    end_impl(name, false)
+/// Controls whether a thread that panicked still commits what it
+/// recorded.
+///
+/// A panic usually leaves spans running, and spans that never ended
+/// don't show up anywhere.  When this is on, every span that is still
+/// running when a thread's data is committed on exit is ended at that
+/// moment (as if by `end_all`), and the data is committed even if the
+/// thread is still unwinding.  This makes it possible to look at what
+/// a crashed thread was doing.  Off by default.
+pub fn set_commit_on_panic(commit_on_panic: bool) {
+    COMMIT_ON_PANIC.store(commit_on_panic, Ordering::SeqCst);
 }
 
-/// Ends the current Span and returns a given result.
+/// Controls whether committing a thread's data ends the spans that are
+/// still running on it, instead of leaving them out.
 ///
-/// This is mainly useful for code generation / plugins where
-/// wrapping all returned expressions is easier than creating
-/// a temporary variable to hold the result.
-pub fn end_with<S: Into<StrCow>, R>(name: S, result: R) -> R {
+/// A span that is running when its thread commits (including when the
+/// thread exits) never ended, so it doesn't show up anywhere.  When
+/// this is on, such spans are ended at the moment of the commit, and
+/// get a `Warn` note named "unclosed" so that the missing `end` can be
+/// tracked down.  Off by default.  See also `unclosed_spans`.
+pub fn set_close_on_commit(close_on_commit: bool) {
+    CLOSE_ON_COMMIT.store(close_on_commit, Ordering::SeqCst);
+}
+
+/// Returns the names of the spans that are running on the current
+/// thread, outermost first.
+///
+/// Spans that are still running when the thread commits are left out
+/// of what's committed (unless `set_close_on_commit` is on), so this
+/// is useful for checking that every `start` was matched by an `end`.
+pub fn unclosed_spans() -> Vec<StrCow> {
+    with_library(|library| {
+        let collector = &library.current;
+        collector.aggregate_stack.iter().map(|(name, _)| name.clone())
+            .chain(collector.id_stack.iter().map(|&id| collector.event(id).name.clone()))
+            .collect()
+    })
+}
+
+/// Ends every running span, after putting a note on it saying that it
+/// was never ended.
+fn close_unclosed(library: &mut Library) {
+    let instant = ns_since_epoch(library.epoch);
+    let collector = &mut library.current;
+    for id in collector.id_stack.clone() {
+        let seq = collector.next_note_seq;
+        collector.event_mut(id).notes.push(Note {
+            name: "unclosed".into(),
+            description: Some("still running when the thread was committed".into()),
+            instant,
+            seq,
+            level: NoteLevel::Warn,
+            track: None,
+            _priv: (),
+        });
+        collector.next_note_seq += 1;
+    }
+    end_all_impl(library);
+}
+
+/// Ends every span that is running on the current thread, innermost
+/// first.
+pub fn end_all() {
+    with_library(end_all_impl);
+}
+
+fn end_all_impl(library: &mut Library) {
+    let epoch = library.epoch;
+    let collector = &mut library.current;
+    library.suppressed_running = 0;
+    library.dropped_running = 0;
+    collector.skip_depth = 0;
+
+    while let Some((name, _)) = collector.aggregate_stack.last().cloned() {
+        let _ = end_aggregate(collector, name, epoch);
+    }
+
+    let timestamp = ns_since_epoch(epoch);
+    let root = collector.id_stack.first().cloned();
+    while let Some(id) = collector.id_stack.pop() {
+        let delta = collector.event_mut(id).finish(timestamp);
+        #[cfg(feature = "skew-check")]
+        check_skew(collector, id, delta, timestamp);
+    }
+
+    let capacity = RING_BUFFER_CAPACITY.load(Ordering::Relaxed);
+    if let Some(root) = root {
+        if capacity > 0 {
+            push_to_ring(collector, root, capacity);
+        }
+    }
+}
+
+/// The number of spans that are running on the current thread, in
+/// the stack that the current mode records into.
+fn running_depth() -> usize {
+    with_library(|library| {
+        let collector = &library.current;
+        if is_aggregate_only() { collector.aggregate_stack.len() } else { collector.id_stack.len() }
+    })
+}
+
+/// Ends the span `name` that was started when `depth` spans were
+/// running, along with every span that is still running inside of it,
+/// innermost first.  Returns `false` (and ends nothing) if that span
+/// isn't running anymore.
+fn end_from_depth(depth: usize, name: StrCow) -> bool {
+    try_with_library(|library| {
+        let name = library.qualify(name);
+        let epoch = library.epoch;
+        let collector = &mut library.current;
+
+        if is_aggregate_only() {
+            if collector.aggregate_stack.get(depth).map(|(running, _)| running) != Some(&name) {
+                return false;
+            }
+            while collector.aggregate_stack.len() > depth {
+                let (innermost, _) = collector.aggregate_stack.last().cloned().unwrap();
+                let _ = end_aggregate(collector, innermost, epoch);
+            }
+            return true;
+        }
+
+        match collector.id_stack.get(depth) {
+            Some(&id) if collector.event(id).name == name => {}
+            _ => return false,
+        }
+        while collector.id_stack.len() > depth {
+            let innermost = *collector.id_stack.last().unwrap();
+            end_current(collector, innermost, false, epoch);
+        }
+        true
+    }).unwrap_or(false)
+}
+
+/// A span started by `enter`, which ends when the token is dropped.
+#[must_use = "The token is immediately dropped after instantiation. This is probably not
+what you want! Consider using a `let` binding to increase its lifetime."]
+pub struct Token {
+    /// The id of the span, if it was recorded as an event
+    id: Option<u32>,
+    name: Option<StrCow>,
+    location: &'static ::std::panic::Location<'static>,
+}
+
+/// Starts a span without naming it yet, and returns a `Token` that
+/// ends it when dropped.
+///
+/// This does as little work as possible up front, which makes it
+/// suitable for very hot code.  The span can be given a name with
+/// `Token::name` at any point before the token is dropped; otherwise
+/// it's named after the place that `enter` was called from, as
+/// "file:line:column".
+#[track_caller]
+pub fn enter() -> Token {
+    let location = ::std::panic::Location::caller();
+    if !is_enabled() {
+        return Token { id: None, name: None, location };
+    }
+    let id = with_library(|library| {
+        if library.skip_start() {
+            return None;
+        }
+
+        let epoch = library.epoch;
+        let collector = &mut library.current;
+        if is_aggregate_only() {
+            collector.aggregate_stack.push(("".into(), ns_since_epoch(epoch)));
+            return None;
+        }
+
+        let id = collector.next_id;
+        collector.next_id += 1;
+        collector.all.push(Event {
+            id,
+            parent: collector.id_stack.last().cloned(),
+            name: "".into(),
+            collapse: false,
+            start: ns_to_stamp(ns_since_epoch(epoch)),
+            end: None,
+            async_end: None,
+            delta: None,
+            notes: vec![],
+            attributes: vec![],
+            metrics: BTreeMap::new(),
+            wait_reason: None,
+            annotation: false,
+            #[cfg(feature = "rss")]
+            rss: Rss::started(),
+        });
+        collector.id_stack.push(id);
+        Some(id)
+    });
+    Token { id, name: None, location }
+}
+
+impl Token {
+    /// Names the span.
+    pub fn name<S: Into<StrCow>>(&mut self, name: S) {
+        self.name = Some(name.into());
+    }
+}
+
+impl Drop for Token {
+    fn drop(&mut self) {
+        if !is_enabled() {
+            return;
+        }
+        let location = self.location;
+        let name = self.name.take().unwrap_or_else(|| {
+            format!("{}:{}:{}", location.file(), location.line(), location.column()).into()
+        });
+        let id = self.id;
+        with_library(|library| {
+            if library.skip_end() {
+                return;
+            }
+
+            let name = library.qualify(name);
+            let epoch = library.epoch;
+            let collector = &mut library.current;
+
+            if is_aggregate_only() {
+                if let Some(last) = collector.aggregate_stack.last_mut() {
+                    last.0 = name.clone();
+                    let _ = end_aggregate(collector, name, epoch);
+                }
+                return;
+            }
+
+            match id {
+                Some(id) if collector.id_stack.last() == Some(&id) => {
+                    collector.event_mut(id).name = name;
+                    end_current(collector, id, false, epoch);
+                }
+                _ if ::std::thread::panicking() => {}
+                _ => panic!("flame::Token for {:?} dropped while another span was running!", &name),
+            }
+        });
+    }
+}
+
+/// Starts a `Span` and also returns a `SpanGuard`.
+///
+/// When the `SpanGuard` is dropped (or `.end()` is called on it),
+/// the span will automatically be ended.
+pub fn start_guard<S: Into<StrCow>>(name: S) -> SpanGuard {
+    let name = name.into();
+    if !is_enabled() {
+        return SpanGuard { name: Some(name), id: None, collapse: false };
+    }
+    let next_id = with_library(|library| library.current.next_id);
+    start(name.clone());
+    let id = with_library(|library| Some(next_id).filter(|id| library.current.id_stack.last() == Some(id)));
+    SpanGuard { name: Some(name), id, collapse: false }
+}
+
+/// Like `start_guard`, but the span is ended with `end_collapse` when
+/// the `SpanGuard` is dropped, so that it's merged into the span
+/// before it if they match.
+pub fn start_guard_collapse<S: Into<StrCow>>(name: S) -> SpanGuard {
+    let mut guard = start_guard(name);
+    guard.collapse = true;
+    guard
+}
+
+/// Starts and ends a `Span` that lasts for the duration of the
+/// function `f`.
+///
+/// The span is ended even if `f` panics.
+pub fn span_of<S, F, R>(name: S, f: F) -> R where
+S: Into<StrCow>,
+F: FnOnce() -> R
+{
+    let _guard = start_guard(name);
+    f()
+}
+
+/// Locks `mutex` inside of a span named `name`, so that the time spent
+/// waiting for it shows up in the profile, and returns the guard.
+///
+/// A note named "lock" on the span says whether the mutex was available
+/// immediately or had to be waited for.  A poisoned mutex is locked
+/// anyway, as if it weren't poisoned.
+pub fn span_lock<'a, T, S: Into<StrCow>>(name: S, mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+    let name = name.into();
+    start(name.clone());
+    let (guard, waited) = match mutex.try_lock() {
+        Ok(guard) => (guard, false),
+        Err(TryLockError::Poisoned(e)) => (e.into_inner(), false),
+        Err(TryLockError::WouldBlock) => (mutex.lock().unwrap_or_else(|e| e.into_inner()), true),
+    };
+    note("lock", Some(if waited { "waited" } else { "available immediately" }));
+    end(name);
+    guard
+}
+
+/// Like `span_of`, but if `f` panics, the panic is caught and returned
+/// as an `Err` (see `std::panic::catch_unwind`) after the span is ended.
+///
+/// This keeps a panic in instrumented code from unwinding any further,
+/// while the profile stays consistent for the rest of the thread.
+pub fn span_of_catch<S, F, R>(name: S, f: F) -> ::std::thread::Result<R> where
+S: Into<StrCow>,
+F: FnOnce() -> R + ::std::panic::UnwindSafe
+{
+    let name = name.into();
+    ::std::panic::catch_unwind(move || span_of(name, f))
+}
+
+/// Like `span_of`, but the span is ended with `end_collapse`, so that
+/// it's folded into the span before it if that has the same name.
+///
+/// This is meant for high-frequency dispatch where the same few names
+/// repeat over and over, like the opcodes of an interpreter: every run
+/// of consecutive spans with the same name becomes a single span whose
+/// `collapse_count` says how many times it ran.  As with `end_collapse`,
+/// only spans that ran inside of another span are folded.
+pub fn dispatch_span<S, F, R>(name: S, f: F) -> R where
+S: Into<StrCow>,
+F: FnOnce() -> R
+{
+    let guard = start_guard(name);
+    let r = f();
+    guard.end_collapse();
+    r
+}
+
+/// Ends a span started with `start_async` when it's dropped, on
+/// whichever thread that happens.
+#[must_use = "The guard is immediately dropped after instantiation. This is probably not
+what you want! Consider using a `let` binding to increase its lifetime."]
+pub struct AsyncSpanGuard {
+    /// Shared with the span's event; `None` if nothing was recorded
+    end: Option<Arc<AtomicU64>>,
+    /// The epoch of the `Library` that the span was recorded in
+    epoch: Instant,
+}
+
+impl Drop for AsyncSpanGuard {
+    fn drop(&mut self) {
+        if let Some(end) = self.end.take() {
+            end.store(ns_since_epoch(self.epoch), Ordering::SeqCst);
+        }
+    }
+}
+
+/// Starts a span that can be held across `.await` points, and returns
+/// a guard that ends it when dropped.
+///
+/// Spans started with `start` have to be ended in the reverse order on
+/// the same thread, which a future that's moved between the threads of
+/// a work-stealing runtime can't promise.  This span is a child of the
+/// span that is currently running, but it isn't pushed onto the stack
+/// of running spans: the guard ends it directly, so it can be dropped
+/// on any thread, at any time.  Spans started while it's running aren't
+/// its children.  The span only shows up in the report once it has
+/// ended; in aggregate-only mode, nothing is recorded.
+///
+/// ```ignore
+/// let response = async {
+///     let _guard = flame::start_async("fetch");
+///     client.get(url).await
+/// };
+/// ```
+pub fn start_async<S: Into<StrCow>>(name: S) -> AsyncSpanGuard {
+    let name = name.into();
+    let (end, epoch) = with_library(|library| {
+        let epoch = library.epoch;
+        if !is_enabled() || library.skipping() || is_aggregate_only() {
+            return (None, Some(epoch));
+        }
+
+        let name = library.qualify(name);
+        let end = Arc::new(AtomicU64::new(u64::MAX));
+        let collector = &mut library.current;
+        let id = collector.next_id;
+        collector.next_id += 1;
+        collector.all.push(Event {
+            id,
+            parent: collector.id_stack.last().cloned(),
+            name,
+            collapse: false,
+            start: ns_to_stamp(ns_since_epoch(epoch)),
+            end: None,
+            async_end: Some(end.clone()),
+            delta: None,
+            notes: vec![],
+            attributes: vec![],
+            metrics: BTreeMap::new(),
+            wait_reason: None,
+            annotation: false,
+            #[cfg(feature = "rss")]
+            rss: Default::default(),
+        });
+        (Some(end), Some(epoch))
+    });
+    AsyncSpanGuard { end, epoch: epoch.unwrap_or_else(Instant::now) }
+}
+
+/// Limits the `name` and `description` of notes recorded from now on
+/// to `max_len` characters.  Longer ones are cut short, and end in
+/// "…" to show that they were.
+///
+/// This bounds the memory used by verbose instrumentation that puts
+/// e.g. whole SQL queries into notes.  Notes aren't limited by default.
+pub fn set_max_note_len(max_len: usize) {
+    MAX_NOTE_LEN.store(max_len, Ordering::SeqCst);
+}
+
+/// Turns recording on or off for all threads.  Recording is on
+/// by default.
+///
+/// While recording is off, `start`, `end` and `note` return right
+/// away without recording anything, so calls to them can be left in
+/// production code at almost no cost.  Only turn recording on or off
+/// while no spans are running; what happens to a span that is started
+/// with recording on and ended with it off (or the other way around)
+/// is unspecified.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// Returns true if recording is on.  See `set_enabled`.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Turns global mode on or off.
+///
+/// In global mode, every thread records into one shared timeline
+/// instead of its own, so the spans and notes of callback threads land
+/// in the same tree as those of the thread that set them up, and show
+/// up in `spans()` on any thread without a `commit_thread`.  Spans that
+/// are started on one thread can even be ended on another.
+///
+/// This is meant for simple scripts and tests.  Every `start`, `end`
+/// and `note` takes a lock, so threads that record at the same time
+/// contend with each other, and spans that they run at the same time
+/// get nested inside of each other in whatever order they happen to
+/// start in.  Only switch modes while no spans are running; what was
+/// recorded in one mode isn't visible to `spans()` in the other.
+pub fn set_global_mode(global: bool) {
+    GLOBAL_MODE.store(global, Ordering::SeqCst);
+}
+
+/// Returns true if global mode is on.  See `set_global_mode`.
+pub fn is_global_mode() -> bool {
+    GLOBAL_MODE.load(Ordering::Relaxed)
+}
+
+/// Records only one of every `n` top-level spans on each thread,
+/// along with everything inside of it; the others (and everything
+/// inside of them) aren't recorded at all.  `n` is 1 by default, which
+/// records everything; 0 is treated as 1.
+///
+/// Starting and ending a span that isn't sampled is cheaper than
+/// recording it, which helps with code that runs spans so often that
+/// recording them all is too slow.  The recorded spans' deltas only
+/// cover the sampled runs, not the total time spent, so multiply
+/// them by `n` to estimate that.  Each thread counts its own top-level
+/// spans, starting with the first one, which is always recorded.
+pub fn set_sample_rate(n: u32) {
+    SAMPLE_RATE.store(n, Ordering::SeqCst);
+}
+
+/// Turns aggregate-only recording on or off for all threads.
+///
+/// In aggregate-only mode, `start` and `end` don't record individual
+/// spans at all.  Instead, the number of times that each name was
+/// ended and the total time spent in it are accumulated, and `spans()`
+/// reports a single collapsed, top-level span per name (with its
+/// `collapse_count` set to the number of times it ran).  Notes are
+/// discarded.
+///
+/// This trades away the structure of the tree for almost no memory
+/// use, which makes it suitable for extremely hot loops.  Switching
+/// modes while spans are running is not supported.
+pub fn set_aggregate_only(aggregate_only: bool) {
+    AGGREGATE_ONLY.store(aggregate_only, Ordering::SeqCst);
+}
+
+/// Returns true if aggregate-only recording is on.
+/// See `set_aggregate_only`.
+pub fn is_aggregate_only() -> bool {
+    AGGREGATE_ONLY.load(Ordering::Relaxed)
+}
+
+/// Keeps only the last `capacity` completed top-level spans
+/// on each thread, or turns this off if `capacity` is 0.
+///
+/// When a top-level span ends, it is moved into a ring buffer,
+/// and once the buffer is full the oldest span in it is thrown
+/// away.  `spans()` reports the contents of the buffer oldest
+/// first, followed by anything that is still running.  This keeps
+/// memory bounded for an always-on profiler that only cares about
+/// the most recent activity.
+///
+/// A top-level span with children that are still running, e.g. ones
+/// started with `start_with_parent`, moves into the buffer once they
+/// have all ended.
+pub fn set_ring_buffer(capacity: usize) {
+    RING_BUFFER_CAPACITY.store(capacity, Ordering::SeqCst);
+}
+
+/// Caps the number of events that each thread keeps, or removes the
+/// cap if `max_events` is `None`.  There is no cap by default.
+///
+/// Once a thread has recorded `max_events` events, the spans that it
+/// starts (and everything that happens inside of them) aren't recorded
+/// until `clear` is called or the thread commits.  They can still be
+/// ended as usual, and are counted by `dropped_span_count`.  This
+/// trades completeness for bounded memory, e.g. in a long-running
+/// server that never clears.
+pub fn set_max_events(max_events: Option<usize>) {
+    MAX_EVENTS.store(max_events.unwrap_or(usize::MAX), Ordering::SeqCst);
+}
+
+/// Returns the number of spans that weren't recorded because of
+/// `set_max_events`, on all threads, since the last `clear`.
+pub fn dropped_span_count() -> u64 {
+    DROPPED_SPANS.load(Ordering::Relaxed)
+}
+
+/// Runs `f` with `prefix` put in front of the name of every span that
+/// is started or ended inside of it, separated by "::".
+///
+/// This keeps spans from different instrumented libraries apart when
+/// they happen to use the same names.  Prefixes nest, so a span
+/// "parse" started inside of `with_prefix("b", ..)` inside of
+/// `with_prefix("a", ..)` is recorded as "a::b::parse".  Spans must
+/// be ended inside of the same prefix that they were started in.
+pub fn with_prefix<F, R>(prefix: &str, f: F) -> R where F: FnOnce() -> R {
+    struct Restore(Option<String>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            with_library(|library| library.prefix = previous);
+        }
+    }
+
+    let previous = with_library(|library| {
+        let prefix = library.qualify(prefix.to_owned().into()).into_owned();
+        library.prefix.replace(prefix)
+    });
+    let _restore = Restore(previous);
+    f()
+}
+
+/// Runs `f` without recording any of the spans or notes inside of it.
+///
+/// Spans started inside of `f` are ignored, and so are their matching
+/// calls to `end`.  This is useful for keeping a noisy, instrumented
+/// helper out of the profile.  Calls to `suppressed` can be nested.
+pub fn suppressed<F, R>(f: F) -> R where F: FnOnce() -> R {
+    struct Restore(bool);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0;
+            with_library(|library| library.suppressed = previous);
+        }
+    }
+
+    let previous = with_library(|library| ::std::mem::replace(&mut library.suppressed, true));
+    let _restore = Restore(previous);
+    f()
+}
+
+/// Like `span_of`, but the span (and everything recorded inside
+/// of it) is thrown away unless it took longer than `threshold`.
+///
+/// This is useful for keeping only the slow instances of an
+/// operation that usually runs quickly.
+pub fn span_of_if_slow<S, F, R>(name: S, threshold: Duration, f: F) -> R where
+S: Into<StrCow>,
+F: FnOnce() -> R
+{
+    let id = with_library(|library| library.current.next_id);
+    let guard = start_guard(name);
+    let r = f();
+    drop(guard);
+
+    let threshold = duration_to_ns(threshold);
+    with_library(|library| {
+        let collector = &mut library.current;
+        let fast = match collector.find_event(id) {
+            Some(event) => event.delta_ns().is_some_and(|delta| delta <= threshold),
+            None => false,
+        };
+        // Spans recorded after it that aren't inside of it, e.g. ones
+        // given another parent, are left alone.
+        if fast {
+            if let Some(positions) = collector.finished_subtree(id) {
+                collector.remove_events(&positions);
+            }
+        }
+    });
+    r
+}
+
+/// Starts a new Span
+pub fn start<S: Into<StrCow>>(name: S) {
+    start_impl(name.into());
+}
+
+/// Like `start`, but returns the time that the span started at, in
+/// nanoseconds since the thread's epoch.
+///
+/// This is the same value that ends up in the span's `start_ns`, so
+/// it can be logged to line the span up with other output.
+/// While recording is off (see `set_enabled`), this returns 0.
+pub fn start_ts<S: Into<StrCow>>(name: S) -> u64 {
+    start_impl(name.into())
+}
+
+fn start_impl(name: StrCow) -> u64 {
+    if !is_enabled() {
+        return 0;
+    }
+    with_library(|library| {
+        let epoch = library.epoch;
+        if library.skip_start() {
+            return ns_since_epoch(epoch);
+        }
+
+        let name = library.qualify(name);
+
+        let collector = &mut library.current;
+        let start_ns = ns_since_epoch(epoch);
+        if is_aggregate_only() {
+            collector.aggregate_stack.push((name, start_ns));
+            return start_ns;
+        }
+
+        let id = collector.next_id;
+        collector.next_id += 1;
+
+        let parent = collector.id_stack.last().cloned();
+
+        let this = Event {
+            id,
+            parent,
+            name,
+            collapse: false,
+            start: ns_to_stamp(start_ns),
+            end: None,
+            async_end: None,
+            delta: None,
+            notes: vec![],
+            attributes: vec![],
+            metrics: BTreeMap::new(),
+            wait_reason: None,
+            annotation: false,
+            #[cfg(feature = "rss")]
+            rss: Rss::started(),
+        };
+        let start_ns = this.start_ns();
+
+        collector.all.push(this);
+        collector.id_stack.push(id);
+
+        #[cfg(feature = "skew-check")]
+        {
+            if let Some(wall_start) = skew::wall_now() {
+                collector.wall_starts.insert(id, wall_start);
+            }
+        }
+        start_ns
+    })
+}
+
+/// Pauses the current span to wait on `reason`, e.g. "db" or "net",
+/// until `resume` is called.
+///
+/// The wait is recorded as a child span with its `wait_reason` set,
+/// so that time spent waiting can be told apart from time spent
+/// working, and different kinds of waits from each other.
+pub fn pause_for(reason: &str) {
+    let next_id = with_library(|library| library.current.next_id);
+    start(format!("[waiting on {}]", reason));
+    update_started(next_id, |event| event.wait_reason = Some(reason.to_owned().into()));
+}
+
+/// Calls `f` on the event that was just started, if it was given the
+/// id `id`.  Spans that aren't recorded as events (e.g. because they
+/// were started inside of `suppressed`) are left alone.
+fn update_started<F: FnOnce(&mut Event)>(id: u32, f: F) {
+    with_library(|library| {
+        let collector = &mut library.current;
+        if collector.id_stack.last() == Some(&id) {
+            f(collector.event_mut(id));
+        }
+    });
+}
+
+/// Times `f` in a child span that is marked as an annotation, and
+/// returns the value that it returned.
+///
+/// This is for things that would otherwise be recorded as a `note`,
+/// but take long enough to be worth measuring.  The span's
+/// `annotation` is set so that renderers can show it like a note.
+pub fn note_as_span<S, F, R>(name: S, f: F) -> R
+where S: Into<StrCow>, F: FnOnce() -> R {
+    let next_id = with_library(|library| library.current.next_id);
+    let _guard = start_guard(name);
+    update_started(next_id, |event| event.annotation = true);
+    f()
+}
+
+/// Ends the wait started by the most recent call to `pause_for`.
+///
+/// # Panics
+///
+/// Panics if the innermost running span isn't a wait.
+pub fn resume() {
+    with_library(|library| {
+        if library.skip_end() {
+            return;
+        }
+
+        let epoch = library.epoch;
+        let collector = &mut library.current;
+
+        if is_aggregate_only() {
+            if let Some((name, _)) = collector.aggregate_stack.last().cloned() {
+                let _ = end_aggregate(collector, name, epoch);
+                return;
+            }
+        } else if let Some(&id) = collector.id_stack.last() {
+            if collector.event(id).wait_reason.is_some() {
+                end_current(collector, id, false, epoch);
+                return;
+            }
+        }
+        panic!("flame::resume() called while not paused!");
+    })
+}
+
+/// Starts a new Span as a child of the span with id `parent_id`
+/// (or as a top-level span if `parent_id` is `None`), and returns
+/// the id of the new span.
+///
+/// Unlike `start`, this doesn't look at or change which span is
+/// currently running, so it can be used to build up a tree from
+/// events that don't arrive in order.  Spans started this way must
+/// be ended with `end_by_id`.
+///
+/// The span isn't recorded, and `UNRECORDED_ID` is returned, when
+/// `start` wouldn't record it either: while recording is turned off,
+/// inside of `suppressed` or a span that isn't being recorded, past
+/// `set_max_events`, or in aggregate-only mode (which needs spans to
+/// be nested).  The same goes for the children of such a span.
+pub fn start_with_parent<S: Into<StrCow>>(name: S, parent_id: Option<u32>) -> u32 {
+    if !is_enabled() {
+        return UNRECORDED_ID;
+    }
+    let name = name.into();
+    with_library(|library| {
+        if skip_explicit(library, parent_id) {
+            return UNRECORDED_ID;
+        }
+        let start_ns = ns_since_epoch(library.epoch);
+        let collector = &mut library.current;
+        let id = push_explicit_event(collector, "start_with_parent", name, parent_id, start_ns, None);
+        #[cfg(feature = "skew-check")]
+        {
+            if let Some(wall_start) = skew::wall_now() {
+                collector.wall_starts.insert(id, wall_start);
+            }
+        }
+        id
+    })
+}
+
+/// The id that `start_with_parent` and `record_span` return for a
+/// span that isn't recorded.  It can be used like any other id: ending it with
+/// `end_by_id` does nothing, and spans started with it as their
+/// parent aren't recorded either.
+pub const UNRECORDED_ID: u32 = u32::MAX;
+
+/// Checks whether a span with an explicit parent should be left out,
+/// for the same reasons that `start` leaves spans out.  Unlike
+/// `Library::skip_start`, this doesn't count the span as running,
+/// since it isn't ended in order.
+fn skip_explicit(library: &mut Library, parent_id: Option<u32>) -> bool {
+    if parent_id == Some(UNRECORDED_ID) || library.skipping() || is_aggregate_only() {
+        return true;
+    }
+    if library.current.all.len() >= MAX_EVENTS.load(Ordering::Relaxed) {
+        DROPPED_SPANS.fetch_add(1, Ordering::Relaxed);
+        return true;
+    }
+    false
+}
+
+/// Records a span that has already finished, with the given start
+/// and end timestamps, as a child of the span with id `parent_id`
+/// (or as a top-level span if `parent_id` is `None`).  Returns the
+/// id of the new span, so that children can be added to it.
+///
+/// This makes it possible to assemble a profile from an external
+/// trace that has its own timestamps, rather than measuring live.
+///
+/// Like with `start_with_parent`, `UNRECORDED_ID` is returned if the
+/// span isn't recorded.  In aggregate-only mode, the span is added to
+/// the aggregates instead (without its parent).
+pub fn record_span<S: Into<StrCow>>(name: S, start_ns: u64, end_ns: u64, parent_id: Option<u32>) -> u32 {
+    let name = name.into();
+    if end_ns < start_ns {
+        panic!("flame::record_span({:?}) given an end ({}) before its start ({})", &name, end_ns, start_ns);
+    }
+    if !is_enabled() {
+        return UNRECORDED_ID;
+    }
+    with_library(|library| {
+        if is_aggregate_only() && parent_id != Some(UNRECORDED_ID) && !library.skipping() {
+            add_aggregate(&mut library.current, name, start_ns, end_ns);
+            return UNRECORDED_ID;
+        }
+        if skip_explicit(library, parent_id) {
+            return UNRECORDED_ID;
+        }
+        push_explicit_event(&mut library.current, "record_span", name, parent_id, start_ns, Some(end_ns))
+    })
+}
+
+fn push_explicit_event(
+    collector: &mut PrivateFrame,
+    caller: &str,
+    name: StrCow,
+    parent_id: Option<u32>,
+    start_ns: u64,
+    end_ns: Option<u64>,
+) -> u32 {
+    if let Some(parent_id) = parent_id {
+        if parent_id >= collector.next_id {
+            panic!("flame::{}({:?}) given an unknown parent id {}", caller, &name, parent_id);
+        }
+    }
+
+    let id = collector.next_id;
+    collector.next_id += 1;
+
+    collector.all.push(Event {
+        id,
+        parent: parent_id,
+        name,
+        collapse: false,
+        start: ns_to_stamp(start_ns),
+        end: end_ns.map(ns_to_stamp),
+        async_end: None,
+        delta: end_ns.map(|end_ns| ns_to_stamp(end_ns).saturating_sub(ns_to_stamp(start_ns))),
+        notes: vec![],
+        attributes: vec![],
+        metrics: BTreeMap::new(),
+        wait_reason: None,
+        annotation: false,
+        #[cfg(feature = "rss")]
+        rss: Default::default(),
+    });
+    id
+}
+
+/// Adds already completed spans, e.g. ones that were recorded by a
+/// background computation and handed back, as children of the span
+/// that is currently running (or as top-level spans if none is).
+///
+/// The spans keep their timestamps, notes and structure; their depths
+/// are fixed up to match where they end up.  Nothing is recorded while
+/// inside of `suppressed`.
+pub fn graft_spans(children: Vec<Span>) {
+    fn graft(collector: &mut PrivateFrame, span: Span, parent: Option<u32>) -> u32 {
+        let id = push_explicit_event(collector, "graft_spans", span.name, parent, span.start_ns, Some(span.end_ns));
+        let event = collector.event_mut(id);
+        event.notes = span.notes;
+        event.attributes = span.attributes;
+        event.metrics = span.metrics;
+        event.wait_reason = span.wait_reason;
+        event.annotation = span.annotation;
+        for child in span.children {
+            graft(collector, child, Some(id));
+        }
+        id
+    }
+
+    fn graft_aggregate(collector: &mut PrivateFrame, span: Span) {
+        let aggregate = collector.aggregates.entry(span.name).or_insert(Aggregate {
+            count: 0,
+            total_ns: 0,
+            first_start_ns: span.start_ns,
+            last_end_ns: span.end_ns,
+        });
+        aggregate.count += span.collapse_count;
+        aggregate.total_ns += span.delta;
+        aggregate.last_end_ns = span.end_ns;
+        for child in span.children {
+            graft_aggregate(collector, child);
+        }
+    }
+
+    with_library(|library| {
+        if library.skipping() {
+            return;
+        }
+
+        let collector = &mut library.current;
+        if is_aggregate_only() {
+            for span in children {
+                graft_aggregate(collector, span);
+            }
+            return;
+        }
+
+        let parent = collector.id_stack.last().cloned();
+        let grafted: Vec<u32> = children.into_iter().map(|span| graft(collector, span, parent)).collect();
+
+        let capacity = RING_BUFFER_CAPACITY.load(Ordering::Relaxed);
+        if capacity > 0 && parent.is_none() {
+            for id in grafted {
+                push_to_ring(collector, id, capacity);
+            }
+        }
+    });
+}
+
+/// Ends the span with the given id, which was started with
+/// `start_with_parent`, and returns the number of nanoseconds that
+/// passed.
+///
+/// Spans started with `start` have to be ended with `end` instead,
+/// since they're running on the thread's stack of spans.
+pub fn end_by_id(id: u32) -> u64 {
+    if id == UNRECORDED_ID || !is_enabled() {
+        return 0;
+    }
+    with_library(|library| {
+        let epoch = library.epoch;
+        let collector = &mut library.current;
+
+        if collector.id_stack.contains(&id) {
+            panic!("flame::end_by_id({}) called on a span that was started with `start`!", id);
+        }
+        let event = match collector.find_event_mut(id) {
+            Some(event) if event.end.is_none() => event,
+            Some(_) => panic!("flame::end_by_id({}) called on a span that already ended!", id),
+            None => panic!("flame::end_by_id({}) called with an unknown id!", id),
+        };
+        let timestamp = ns_since_epoch(epoch);
+        let delta = event.finish(timestamp);
+        #[cfg(feature = "skew-check")]
+        check_skew(collector, id, delta, timestamp);
+
+        // Its top-level span may have been waiting on it to move into
+        // the ring buffer.
+        let capacity = RING_BUFFER_CAPACITY.load(Ordering::Relaxed);
+        if capacity > 0 {
+            let mut root = id;
+            while let Some(parent) = collector.find_event(root).and_then(|event| event.parent) {
+                root = parent;
+            }
+            push_to_ring(collector, root, capacity);
+        }
+        delta
+    })
+}
+
+fn end_impl<S: Into<StrCow>>(name: S, collapse: bool) -> u64 {
+    match try_end_impl(name.into(), collapse) {
+        Ok(delta) => delta,
+        Err(FlameError::Disabled) => 0,
+        Err(_) if ::std::thread::panicking() => 0,
+        Err(FlameError::NoActiveSpan { name }) =>
+            panic!("flame::end({:?}) called without a currently running span!", &name),
+        Err(FlameError::NameMismatch { expected, found, suggestion: None }) =>
+            panic!("flame::end({}) attempted to end {}", &found, &expected),
+        Err(FlameError::NameMismatch { expected, found, suggestion: Some(suggestion) }) =>
+            panic!("flame::end({}) attempted to end {}; did you mean {}?", &found, &expected, &suggestion),
+    }
+}
+
+/// Returns the number of single character edits that it takes
+/// to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0 ..= b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for (i, ca) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == cb { 0 } else { 1 };
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        ::std::mem::swap(&mut previous, &mut current);
+    }
+
+    previous[b.len()]
+}
+
+/// Finds the running span name that `name` was most likely meant to be.
+fn suggest_name<'a, I>(name: &str, running: I) -> Option<StrCow>
+where I: Iterator<Item = &'a StrCow> {
+    let max_distance = (name.chars().count() + 1) / 3;
+    running
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, _)| distance > 0 && distance <= max_distance)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, candidate)| candidate.clone())
+}
+
+fn try_end_impl(name: StrCow, collapse: bool) -> Result<u64, FlameError> {
+    if !is_enabled() {
+        return Err(FlameError::Disabled);
+    }
+    try_with_library(|library| {
+        if library.skip_end() {
+            return Ok(0);
+        }
+
+        let name = library.qualify(name);
+        let epoch = library.epoch;
+        let collector = &mut library.current;
+
+        if is_aggregate_only() {
+            return end_aggregate(collector, name, epoch);
+        }
+
+        let current_id = match collector.id_stack.last() {
+            Some(id) => *id,
+            None => return Err(FlameError::NoActiveSpan { name }),
+        };
+
+        if collector.event(current_id).name != name {
+            let running = collector.id_stack.iter().rev().map(|&id| &collector.event(id).name);
+            return Err(FlameError::NameMismatch {
+                expected: collector.event(current_id).name.clone(),
+                suggestion: suggest_name(&name, running),
+                found: name,
+            });
+        }
+
+        Ok(end_current(collector, current_id, collapse, epoch))
+    }).unwrap_or(Ok(0))
+}
+
+/// Ends the innermost running span, `current_id`, and returns
+/// the number of nanoseconds that it took.
+fn end_current(collector: &mut PrivateFrame, current_id: u32, collapse: bool, epoch: Instant) -> u64 {
+    collector.id_stack.pop();
+
+    let timestamp = ns_since_epoch(epoch);
+    let event = collector.event_mut(current_id);
+    let delta = event.finish(timestamp);
+    event.collapse = collapse;
+
+    #[cfg(feature = "skew-check")]
+    check_skew(collector, current_id, delta, timestamp);
+
+    let capacity = RING_BUFFER_CAPACITY.load(Ordering::Relaxed);
+    if capacity > 0 && collector.id_stack.is_empty() {
+        push_to_ring(collector, current_id, capacity);
+    }
+
+    delta
+}
+
+/// Puts a "clock skew" note on the span `id`, which just ended after
+/// `delta` nanoseconds, if the wall clock disagrees.  This forgets
+/// when the span started on the wall clock, whether or not it does.
+#[cfg(feature = "skew-check")]
+fn check_skew(collector: &mut PrivateFrame, id: u32, delta: u64, timestamp: u64) {
+    let skew = collector.wall_starts.remove(&id)
+        .and_then(|wall_start| skew::check(wall_start, delta));
+    if let Some(description) = skew {
+        let seq = collector.next_note_seq;
+        collector.event_mut(id).notes.push(Note {
+            name: "clock skew".into(),
+            description: Some(description.into()),
+            instant: timestamp,
+            seq,
+            level: NoteLevel::Warn,
+            track: None,
+            _priv: ()
+        });
+        collector.next_note_seq += 1;
+    }
+}
+
+/// Moves the completed top-level span `id` (and everything
+/// recorded inside of it) out of `all` and into the ring buffer.
+fn push_to_ring(collector: &mut PrivateFrame, id: u32, capacity: usize) {
+    // Spans that are still running inside of it, e.g. ones started with
+    // `start_with_parent`, keep it where it is until they've ended.
+    let positions = match collector.finished_subtree(id) {
+        Some(positions) => positions,
+        None => return,
+    };
+    let spans = convert_events_to_span(positions.iter().map(|&position| &collector.all[position]));
+    collector.remove_events(&positions);
+
+    collector.ring.extend(spans);
+    while collector.ring.len() > capacity {
+        collector.ring.pop_front();
+    }
+}
+
+fn end_aggregate(collector: &mut PrivateFrame, name: StrCow, epoch: Instant) -> Result<u64, FlameError> {
+    match collector.aggregate_stack.last() {
+        None => return Err(FlameError::NoActiveSpan { name }),
+        Some((expected, _)) if *expected != name => {
+            let running = collector.aggregate_stack.iter().rev().map(|(name, _)| name);
+            return Err(FlameError::NameMismatch {
+                expected: expected.clone(),
+                suggestion: suggest_name(&name, running),
+                found: name,
+            });
+        }
+        Some(_) => {}
+    }
+
+    let (name, start_ns) = collector.aggregate_stack.pop().unwrap();
+    Ok(add_aggregate(collector, name, start_ns, ns_since_epoch(epoch)))
+}
+
+/// Adds a span that ran from `start_ns` to `end_ns` to the aggregate
+/// for `name`, and returns its delta.
+fn add_aggregate(collector: &mut PrivateFrame, name: StrCow, start_ns: u64, end_ns: u64) -> u64 {
+    let delta = end_ns.saturating_sub(start_ns);
+    let aggregate = collector.aggregates.entry(name).or_insert(Aggregate {
+        count: 0,
+        total_ns: 0,
+        first_start_ns: start_ns,
+        last_end_ns: end_ns,
+    });
+    aggregate.count += 1;
+    aggregate.total_ns += delta;
+    aggregate.last_end_ns = end_ns;
+    delta
+}
+
+/// Ends the current Span and returns the number
+/// of nanoseconds that passed.
+pub fn end<S: Into<StrCow>>(name: S) -> u64 {
+    end_impl(name, false)
+}
+
+/// Like `end`, but returns an error instead of panicking if no span is
+/// running (`FlameError::NoActiveSpan`) or if `name` doesn't match the
+/// current Span (`FlameError::NameMismatch`).  Nothing is ended when
+/// an error is returned.
+///
+/// This is the same as `checked::end`.
+pub fn try_end<S: Into<StrCow>>(name: S) -> Result<u64, FlameError> {
+    try_end_impl(name.into(), false)
+}
+
+/// Ends the current Span and returns a given result.
+///
+/// This is mainly useful for code generation / plugins where
+/// wrapping all returned expressions is easier than creating
+/// a temporary variable to hold the result.
+pub fn end_with<S: Into<StrCow>, R>(name: S, result: R) -> R {
     end_impl(name, false);
     result
 }
@@ -420,108 +2101,641 @@ pub fn end_collapse<S: Into<StrCow>>(name: S) -> u64 {
 }
 
 /// Records a note on the current Span.
-pub fn note<S: Into<StrCow>>(name: S, description: Option<S>) {
-    let name = name.into();
-    let description = description.map(Into::into);
+///
+/// The name and the description can be of different types, e.g.
+/// `note("retry", Some(format!("attempt {}", n)))`.  A note without a
+/// description needs the type spelled out, as in `None::<&str>`.
+pub fn note<N: Into<StrCow>, D: Into<StrCow>>(name: N, description: Option<D>) {
+    note_impl(name.into(), description.map(Into::into), None, NoteLevel::Info, false);
+}
+
+/// Records a note with the given level on the current Span.
+pub fn note_with_level<N: Into<StrCow>, D: Into<StrCow>>(level: NoteLevel, name: N, description: Option<D>) {
+    note_impl(name.into(), description.map(Into::into), None, level, false);
+}
+
+/// Records a note on the current Span, on the sub-timeline named `track`.
+///
+/// Tracks group the notes of a span that does several things side by
+/// side, so that each of them can be shown as its own timeline.  The
+/// track is stored in the note's `track`; see also `Span::notes_by_track`.
+pub fn note_on_track<S: Into<StrCow>, D: Into<StrCow>>(track: S, name: S, description: Option<D>) {
+    note_impl(name.into(), description.map(Into::into), Some(track.into()), NoteLevel::Info, false);
+}
+
+/// Records a note on the current Span, unless the span already
+/// has a note with the same name.
+///
+/// This is useful for things like retry loops, where the same
+/// note could otherwise be recorded hundreds of times.
+pub fn note_once<N: Into<StrCow>, D: Into<StrCow>>(name: N, description: Option<D>) {
+    note_impl(name.into(), description.map(Into::into), None, NoteLevel::Info, true);
+}
+
+/// Attaches a typed `value` to the current Span under `key`, e.g.
+/// `flame::attr("rows", AttrValue::U64(1234))`, for the exporters to
+/// keep as metadata.
+pub fn attr<S: Into<StrCow>>(key: S, value: AttrValue) {
+    let key = key.into();
+    if !update_current_event(|event| event.attributes.push((key.clone(), value))) {
+        panic!("flame::attr({}) called without a currently running span!", &key);
+    }
+}
+
+/// Attaches a named number to the currently running span, such as the
+/// number of rows a query returned or the hit rate of a cache.
+/// Setting the same metric again replaces its value.
+///
+/// The metrics end up in `Span::metrics`, and in the JSON output.
+///
+/// # Panics
+///
+/// Panics if no span is running, like `note`.
+pub fn set_span_metric<S: Into<StrCow>>(key: S, value: f64) {
+    let key = key.into();
+    if !update_current_event(|event| { event.metrics.insert(key.clone(), value); }) {
+        panic!("flame::set_span_metric({}) called without a currently running span!", &key);
+    }
+}
+
+/// Calls `f` on the event of the innermost running span.  Returns
+/// false if there is no running span; nothing is changed while
+/// recording is disabled, inside of `suppressed`, or in aggregate-only
+/// mode.
+fn update_current_event<F: FnOnce(&mut Event)>(f: F) -> bool {
+    if !is_enabled() {
+        return true;
+    }
+    try_with_library(|library| {
+        if library.skipping() {
+            return true;
+        }
+
+        let collector = &mut library.current;
+        if is_aggregate_only() {
+            return !collector.aggregate_stack.is_empty();
+        }
+
+        match collector.id_stack.last() {
+            Some(&id) => {
+                f(collector.event_mut(id));
+                true
+            }
+            None => false,
+        }
+    }).unwrap_or(true)
+}
+
+fn note_impl(name: StrCow, description: Option<StrCow>, track: Option<StrCow>, level: NoteLevel, once: bool) {
+    if let Err(FlameError::NoActiveSpan { name }) = try_note_impl(name, description, track, level, once) {
+        panic!("flame::note({}) called without a currently running span!", &name);
+    }
+}
+
+/// Cuts `text` down to `MAX_NOTE_LEN` characters followed by "…",
+/// if it's longer than that.
+fn truncate_note_text(text: StrCow) -> StrCow {
+    let max_len = MAX_NOTE_LEN.load(Ordering::Relaxed);
+    match text.char_indices().nth(max_len) {
+        Some((end, _)) => format!("{}…", &text[.. end]).into(),
+        None => text,
+    }
+}
+
+fn try_note_impl(
+    name: StrCow,
+    description: Option<StrCow>,
+    track: Option<StrCow>,
+    level: NoteLevel,
+    once: bool,
+) -> Result<(), FlameError> {
+    if !is_enabled() {
+        return Err(FlameError::Disabled);
+    }
+    try_with_library(|library| {
+        if library.skipping() {
+            return Ok(());
+        }
 
-    LIBRARY.with(|library| {
-        let mut library = library.borrow_mut();
         let epoch = library.epoch;
 
         let collector = &mut library.current;
 
+        if is_aggregate_only() {
+            return if collector.aggregate_stack.is_empty() {
+                Err(FlameError::NoActiveSpan { name })
+            } else {
+                Ok(())
+            };
+        }
+
         let current_id = match collector.id_stack.last() {
             Some(id) => *id,
-            None => panic!("flame::note({}, {:?}) called without a currently running span!",
-                           &name, &description)
+            None => return Err(FlameError::NoActiveSpan { name }),
         };
 
-        let event = &mut collector.all[current_id as usize];
+        let name = truncate_note_text(name);
+        let description = description.map(truncate_note_text);
+        let seq = collector.next_note_seq;
+        let event = collector.event_mut(current_id);
+        if once && event.notes.iter().any(|note| note.name == name) {
+            return Ok(());
+        }
+
         event.notes.push(Note {
             name,
             description,
             instant: ns_since_epoch(epoch),
+            seq,
+            level,
+            track,
             _priv: ()
         });
-    });
+        collector.next_note_seq += 1;
+        Ok(())
+    }).unwrap_or(Ok(()))
+}
+
+/// Returns the spans that are running on the current thread, innermost
+/// first, along with how long each of them has been running for.
+pub fn current_context() -> ProfileContext {
+    with_library(|library| {
+        let now = ns_since_epoch(library.epoch);
+        let collector = &library.current;
+        let mut spans: Vec<(StrCow, u64)> = collector.id_stack.iter().map(|&id| {
+            let event = collector.event(id);
+            (event.name.clone(), now.saturating_sub(event.start_ns()))
+        }).collect();
+        spans.extend(collector.aggregate_stack.iter().map(|&(ref name, start_ns)| (name.clone(), now.saturating_sub(start_ns))));
+        spans.reverse();
+        ProfileContext { spans }
+    })
 }
 
 /// Clears all of the recorded info that Flame has
 /// tracked.
+///
+/// This throws away what every thread has committed as well as what
+/// the current thread is recording.  To only reset the current thread,
+/// use `clear_current_thread`.
 pub fn clear() {
     if ::std::thread::panicking() { return; }
-    LIBRARY.with(|library| {
-        let mut library = library.borrow_mut();
-        library.current = PrivateFrame {
-            all: vec![],
-            id_stack: vec![],
-            next_id: 0,
-        };
+    with_library(|library| reset_all(library, &mut ALL_THREADS.lock().unwrap()));
+}
+
+/// Throws away what `library` is recording, everything in `committed`
+/// (which is `ALL_THREADS`), and the registered threads and dropped
+/// span count that go along with them.
+fn reset_all(library: &mut Library, committed: &mut Vec<(usize, Option<String>, PrivateFrame)>) {
+    library.current = PrivateFrame::new();
+    library.epoch = Instant::now();
+    committed.clear();
+    REGISTERED_THREADS.lock().unwrap().clear();
+    DROPPED_SPANS.store(0, Ordering::SeqCst);
+}
+
+/// Throws away what the current thread has recorded so far (and
+/// restarts its epoch), without touching anything that other threads
+/// have committed.
+///
+/// Unlike `clear`, this is safe to call from one worker thread while
+/// others keep recording, e.g. between requests in a server that
+/// handles each request on its own thread.
+pub fn clear_current_thread() {
+    if ::std::thread::panicking() { return; }
+    with_library(|library| {
+        library.current = PrivateFrame::new();
         library.epoch = Instant::now();
     });
+}
+
+/// Releases memory that was allocated for recording spans but
+/// isn't needed anymore, on the current thread and in the frames
+/// that other threads have committed.
+///
+/// Recording never shrinks its buffers by itself, so a long-lived
+/// thread that alternates between big bursts of spans and `clear`
+/// or a ring buffer (see `set_ring_buffer`) keeps the memory of its
+/// biggest burst around.  Call this after such a burst to give it
+/// back.
+pub fn shrink_to_fit() {
+    if ::std::thread::panicking() { return; }
+    with_library(|library| library.current.shrink_to_fit());
+
+    if let Ok(mut handle) = ALL_THREADS.lock() {
+        for &mut (_, _, ref mut frm) in handle.iter_mut() {
+            frm.shrink_to_fit();
+        }
+        handle.shrink_to_fit();
+    }
+}
+
+/// Returns how many events the current thread can record before
+/// it has to allocate more memory.
+pub fn event_capacity() -> usize {
+    with_library(|library| library.current.all.capacity())
+}
+
+/// Returns roughly how much memory, in bytes, is taken up by what the
+/// current thread and every committed thread have recorded.
+///
+/// This counts the room for events (used or not), and the names and
+/// notes that they own, but not everything else that's attached to
+/// them.  It's meant as an order of magnitude, e.g. for deciding when
+/// to `clear`.
+pub fn approx_memory_bytes() -> usize {
+    let current = with_library(|library| library.current.approx_memory_bytes());
+    let committed = ALL_THREADS.lock()
+        .map(|handle| handle.iter().map(|(_, _, frm)| frm.approx_memory_bytes()).sum())
+        .unwrap_or(0);
+    current + committed
+}
 
-    let mut handle = ALL_THREADS.lock().unwrap();
-    handle.clear();
+/// Clears all of the recorded info that Flame has
+/// tracked and returns it.
+///
+/// This behaves like `threads()` followed by `clear()`,
+/// except that no other thread can commit data in between
+/// the two, so nothing recorded is lost or returned twice.
+pub fn drain_all() -> Vec<Thread> {
+    if ::std::thread::panicking() { return vec![]; }
+
+    // In global mode, the library has to be locked before `ALL_THREADS`
+    with_library(|library| {
+        let mut handle = ALL_THREADS.lock().unwrap();
+        let mut out = vec![ Thread {
+            id: ::thread_id::get(),
+            name: ::std::thread::current().name().map(Into::into),
+            spans: library.current.spans(),
+            epoch_offset_ns: epoch_offset_ns(library.epoch),
+            spawned_at: library.spawned_at.clone(),
+            _priv: (),
+        }];
+
+        for (id, name, frm) in handle.drain(..) {
+            out.push(Thread {
+                id,
+                name,
+                spans: frm.spans(),
+                epoch_offset_ns: frm.epoch_offset_ns,
+                spawned_at: frm.spawned_at,
+                _priv: (),
+            });
+        }
+
+        reset_all(library, &mut handle);
+        out
+    })
 }
 
 /// Returns a list of spans from the current thread
 pub fn spans() -> Vec<Span> {
     if ::std::thread::panicking() { return vec![]; }
-    LIBRARY.with(|library| {
-        let library = library.borrow();
-        let cur = &library.current;
-        convert_events_to_span(cur.all.iter())
+    with_library(|library| {
+        library.current.spans()
     })
 }
 
-pub fn threads() -> Vec<Thread> {
-    if ::std::thread::panicking() { return vec![]; }
+/// Like `spans()`, but every note also shows up as a zero-duration
+/// child of the span that it was recorded on, named after the note and
+/// placed at its `instant` among the other children.
+///
+/// This lets visualizers that don't know about notes still show them
+/// as markers.  The notes themselves are left in place.
+pub fn spans_with_note_markers() -> Vec<Span> {
+    fn add_markers(span: &mut Span) {
+        for child in &mut span.children {
+            add_markers(child);
+        }
+        for note in &span.notes {
+            let marker = Span {
+                name: note.name.clone(),
+                start_ns: note.instant,
+                end_ns: note.instant,
+                delta: 0,
+                depth: span.depth.saturating_add(1),
+                children: vec![],
+                notes: vec![],
+                attributes: vec![],
+                metrics: BTreeMap::new(),
+                collapse_count: 1,
+                wait_reason: None,
+                annotation: false,
+                #[cfg(feature = "rss")]
+                rss: Default::default(),
+                inclusive_ns: None,
+                exclusive_ns: None,
+                collapsable: false,
+                _priv: (),
+            };
+            let at = span.children.iter().position(|child| child.start_ns > note.instant).unwrap_or(span.children.len());
+            span.children.insert(at, marker);
+        }
+    }
+
+    let mut spans = spans();
+    for span in &mut spans {
+        add_markers(span);
+    }
+    spans
+}
+
+pub fn threads() -> Vec<Thread> {
+    if ::std::thread::panicking() { return vec![]; }
+
+    let my_thread_name = ::std::thread::current().name().map(Into::into);
+    let my_thread_id = ::thread_id::get();
+
+    let (my_spans, my_epoch_offset_ns, my_spawned_at) = with_library(|library| {
+        (library.current.spans(), epoch_offset_ns(library.epoch), library.spawned_at.clone())
+    });
+
+    let mut out = vec![ Thread {
+        id: my_thread_id,
+        name: my_thread_name,
+        spans: my_spans,
+        epoch_offset_ns: my_epoch_offset_ns,
+        spawned_at: my_spawned_at,
+        _priv: (),
+    }];
+
+    if let Ok(mut handle) = ALL_THREADS.lock() {
+        for &(id, ref name, ref frm) in &*handle {
+            out.push(Thread {
+                id,
+                name: name.clone(),
+                spans: frm.spans(),
+                epoch_offset_ns: frm.epoch_offset_ns,
+                spawned_at: frm.spawned_at.clone(),
+                _priv: (),
+            });
+        }
+    }
+
+    out
+}
+
+/// Returns the spans recorded by the thread with the given id, as
+/// found in `Thread::id`, or `None` if that thread hasn't committed
+/// anything.  A thread that committed more than once gets the spans
+/// from all of its commits, in order.
+///
+/// Unlike `threads()`, only that one thread's spans are converted.
+/// The calling thread's own id always works, even before it commits.
+pub fn spans_for_thread(id: usize) -> Option<Vec<Span>> {
+    if ::std::thread::panicking() { return None; }
+
+    if id == ::thread_id::get() {
+        return Some(spans());
+    }
+
+    let handle = ALL_THREADS.lock().ok()?;
+    let mut frames = handle.iter().filter(|&&(thread_id, _, _)| thread_id == id).peekable();
+    frames.peek()?;
+    Some(frames.flat_map(|(_, _, frm)| frm.spans()).collect())
+}
+
+/// A copy of everything that was recorded at one point in time, taken
+/// by `snapshot`.
+///
+/// Taking a snapshot only copies the recorded events; they're turned
+/// into spans the first time that they're asked for, and the spans are
+/// kept for later calls.  A monitoring loop can take one snapshot and
+/// query it repeatedly without locking anything or rebuilding trees.
+#[derive(Debug)]
+pub struct Snapshot {
+    frames: Vec<SnapshotFrame>,
+}
+
+#[derive(Debug)]
+struct SnapshotFrame {
+    id: usize,
+    name: Option<String>,
+    frame: PrivateFrame,
+    spans: OnceCell<Vec<Span>>,
+}
+
+impl SnapshotFrame {
+    fn spans(&self) -> &[Span] {
+        self.spans.get_or_init(|| self.frame.spans())
+    }
+}
+
+impl Snapshot {
+    /// Returns the spans recorded by the thread with the given id, like
+    /// `spans_for_thread`, or `None` if the snapshot has nothing for
+    /// that thread.
+    pub fn spans_for(&self, id: usize) -> Option<Vec<Span>> {
+        let mut frames = self.frames.iter().filter(|frame| frame.id == id).peekable();
+        frames.peek()?;
+        Some(frames.flat_map(|frame| frame.spans().iter().cloned()).collect())
+    }
 
-    let my_thread_name = ::std::thread::current().name().map(Into::into);
-    let my_thread_id = ::thread_id::get();
+    /// Returns every thread in the snapshot, like `threads()`.
+    pub fn all_threads(&self) -> Vec<Thread> {
+        self.frames.iter().map(|frame| Thread {
+            id: frame.id,
+            name: frame.name.clone(),
+            spans: frame.spans().to_vec(),
+            epoch_offset_ns: frame.frame.epoch_offset_ns,
+            spawned_at: frame.frame.spawned_at.clone(),
+            _priv: (),
+        }).collect()
+    }
+}
 
-    let mut out = vec![ Thread {
-        id: my_thread_id,
-        name: my_thread_name,
-        spans: spans(),
-        _priv: (),
-    }];
+/// Takes a `Snapshot` of what the current thread has recorded so far
+/// and of every committed thread.
+pub fn snapshot() -> Snapshot {
+    let mut frames = vec![];
+    if ::std::thread::panicking() { return Snapshot { frames }; }
+
+    with_library(|library| {
+        let mut frame = library.current.clone();
+        frame.epoch_offset_ns = epoch_offset_ns(library.epoch);
+        frame.spawned_at = library.spawned_at.clone();
+        frames.push(SnapshotFrame {
+            id: ::thread_id::get(),
+            name: ::std::thread::current().name().map(Into::into),
+            frame,
+            spans: OnceCell::new(),
+        });
+    });
 
-    if let Ok(mut handle) = ALL_THREADS.lock() {
-        for &(id, ref name, ref frm) in &*handle {
-            out.push(Thread {
+    if let Ok(handle) = ALL_THREADS.lock() {
+        for &(id, ref name, ref frame) in &*handle {
+            frames.push(SnapshotFrame {
                 id,
                 name: name.clone(),
-                spans: convert_events_to_span(frm.all.iter()),
-                _priv: (),
+                frame: frame.clone(),
+                spans: OnceCell::new(),
             });
         }
     }
 
+    Snapshot { frames }
+}
+
+/// Like `threads()`, but also includes every thread that was
+/// registered (see `register_thread`) without recording anything,
+/// with an empty list of spans.
+///
+/// This makes it possible to see that a thread ran without
+/// doing any of the profiled work.
+pub fn threads_include_empty() -> Vec<Thread> {
+    let mut out = threads();
+    if let Ok(handle) = REGISTERED_THREADS.lock() {
+        for &(id, ref name) in &*handle {
+            if !out.iter().any(|thread| thread.id == id) {
+                out.push(Thread {
+                    id,
+                    name: name.clone(),
+                    spans: vec![],
+                    epoch_offset_ns: 0,
+                    spawned_at: None,
+                    _priv: (),
+                });
+            }
+        }
+    }
+    out
+}
+
+/// Like `threads()`, but sorted by name and then by id, instead of
+/// starting with the calling thread and going on in the order that
+/// threads committed in.  Threads without a name come first.
+///
+/// This keeps the order of reports the same from run to run, as long
+/// as the threads are named.
+pub fn threads_sorted() -> Vec<Thread> {
+    let mut out = threads();
+    out.sort_by(|a, b| (&a.name, a.id).cmp(&(&b.name, b.id)));
     out
 }
 
+/// Returns the name of every span recorded on any thread,
+/// sorted and without duplicates.
+pub fn distinct_names() -> BTreeSet<StrCow> {
+    fn collect(spans: &[Span], names: &mut BTreeSet<StrCow>) {
+        for span in spans {
+            if !names.contains(&span.name) {
+                names.insert(span.name.clone());
+            }
+            collect(&span.children, names);
+        }
+    }
+
+    let mut names = BTreeSet::new();
+    for thread in threads() {
+        collect(&thread.spans, &mut names);
+    }
+    names
+}
+
 /// Prints all of the frames to stdout.
 pub fn debug() {
     if ::std::thread::panicking() { return; }
-    LIBRARY.with(|library| {
+    with_library(|library| {
         println!("{:?}", library);
     });
 }
 
-pub fn dump_text_to_writer<W: Write>(mut out: W) -> Result<(), IoError>  {
-    fn print_span<W: Write>(span: &Span, out: &mut W) -> Result<f32, IoError> {
+/// Options for `dump_text_to_writer_with_options`.
+#[derive(Debug, Clone, Default)]
+pub struct TextDumpOptions {
+    /// Show how many direct children each span has, e.g. `[3 children]`
+    pub show_child_count: bool,
+    /// When showing child counts, mark spans without children with `[leaf]`
+    pub mark_leaves: bool,
+}
+
+/// The unit that `dump_text_to_writer_unit` prints times in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimeUnit {
+    /// Nanoseconds, printed as `ns`
+    Nanos,
+    /// Microseconds, printed as `µs`
+    Micros,
+    /// Milliseconds, printed as `ms`
+    #[default]
+    Millis,
+    /// Seconds, printed as `s`
+    Seconds,
+}
+
+impl TimeUnit {
+    /// Converts `ns` nanoseconds into this unit.
+    fn scale(self, ns: u64) -> f32 {
+        match self {
+            TimeUnit::Nanos => ns as f32,
+            TimeUnit::Micros => ns as f32 / 1000.0,
+            TimeUnit::Millis => ns as f32 / 1000000.0,
+            TimeUnit::Seconds => ns as f32 / 1000000000.0,
+        }
+    }
+
+    fn suffix(self) -> &'static str {
+        match self {
+            TimeUnit::Nanos => "ns",
+            TimeUnit::Micros => "µs",
+            TimeUnit::Millis => "ms",
+            TimeUnit::Seconds => "s",
+        }
+    }
+}
+
+pub fn dump_text_to_writer<W: Write>(out: W) -> Result<(), IoError>  {
+    dump_text_to_writer_with_options(out, &TextDumpOptions::default())
+}
+
+/// Like `dump_text_to_writer`, but prints times in `unit` instead of
+/// in milliseconds, e.g. to make spans that take a few microseconds
+/// readable.
+pub fn dump_text_to_writer_unit<W: Write>(out: W, unit: TimeUnit) -> Result<(), IoError>  {
+    dump_text_impl(out, &TextDumpOptions::default(), unit)
+}
+
+pub fn dump_text_to_writer_with_options<W: Write>(out: W, options: &TextDumpOptions) -> Result<(), IoError>  {
+    dump_text_impl(out, options, TimeUnit::Millis)
+}
+
+fn dump_text_impl<W: Write>(mut out: W, options: &TextDumpOptions, unit: TimeUnit) -> Result<(), IoError>  {
+    fn print_span<W: Write>(span: &Span, out: &mut W, options: &TextDumpOptions, unit: TimeUnit) -> Result<f32, IoError> {
         let mut buf = String::new();
         for _ in 0 .. span.depth {
             buf.push_str("  ");
         }
         buf.push_str("| ");
-        let ms = span.delta as f32 / 1000000.0;
-        buf.push_str(&format!("{}: {}ms", span.name, ms));
+        let time = unit.scale(span.delta);
+        buf.push_str(&format!("{}: {}{}", span.name, time, unit.suffix()));
+        if options.show_child_count {
+            match span.children.len() {
+                0 if options.mark_leaves => buf.push_str(" [leaf]"),
+                0 => {}
+                1 => buf.push_str(" [1 child]"),
+                n => buf.push_str(&format!(" [{} children]", n)),
+            }
+        }
         writeln!(out, "{}", buf)?;
-        let mut missing = ms;
+
+        for note in &span.notes {
+            let mut buf = String::new();
+            for _ in 0 ..= span.depth {
+                buf.push_str("  ");
+            }
+            buf.push_str("* ");
+            buf.push_str(&note.name);
+            if let Some(ref description) = note.description {
+                buf.push_str(&format!(": {}", description));
+            }
+            let offset = unit.scale(note.instant.saturating_sub(span.start_ns));
+            buf.push_str(&format!(" @ +{}{}", offset, unit.suffix()));
+            writeln!(out, "{}", buf)?;
+        }
+
+        let mut missing = time;
         for child in &span.children {
-            missing -= print_span(child, out)?;
+            missing -= print_span(child, out, options, unit)?;
         }
 
         if !span.children.is_empty() {
@@ -530,42 +2744,283 @@ pub fn dump_text_to_writer<W: Write>(mut out: W) -> Result<(), IoError>  {
                 buf.push_str("  ");
             }
             buf.push_str("+ ");
-            buf.push_str(&format!("{}ms", missing));
+            buf.push_str(&format!("{}{}", missing, unit.suffix()));
             writeln!(out, "{}", buf)?;
         }
 
-        Ok(ms)
+        Ok(time)
     }
 
-    for thread in threads() {
+    let threads = threads();
+    for thread in &threads {
         writeln!(out, "THREAD: {}", thread.id)?;
-        for span in thread.spans {
-            print_span(&span, &mut out)?;
+        for span in &thread.spans {
+            print_span(span, &mut out, options, unit)?;
         }
         writeln!(out)?;
     }
+
+    let spans: Vec<Span> = threads.into_iter().flat_map(|thread| thread.spans).collect();
+    if let Some(warning) = zero_delta_warning(&spans) {
+        writeln!(out, "warning: {}", warning)?;
+    }
     Ok(())
 }
 
+/// Measures the smallest step that the clock used for timing spans
+/// can take, by reading it until its value changes.  That's the clock
+/// installed with `set_clock`, if there is one.
+///
+/// Spans that are shorter than this can come back with a `delta` of 0.
+/// Returns `None` if the clock didn't change within 100ms, e.g.
+/// because it's a fake clock that only moves when it's told to.
+pub fn clock_resolution() -> Option<Duration> {
+    let give_up_after = Duration::from_millis(100);
+    let epoch = Instant::now();
+    let mut resolution = u64::MAX;
+    for _ in 0 .. 16 {
+        let waiting_since = Instant::now();
+        let start = ns_since_epoch(epoch);
+        loop {
+            let now = ns_since_epoch(epoch);
+            if now != start {
+                resolution = resolution.min(now.abs_diff(start));
+                break;
+            }
+            if waiting_since.elapsed() > give_up_after {
+                return None;
+            }
+        }
+    }
+    Some(Duration::from_nanos(resolution))
+}
+
+/// Reported by `zero_delta_warning` when a large share of spans took
+/// no measurable time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZeroDeltaWarning {
+    /// The number of spans with a `delta` of 0
+    pub zero_delta: usize,
+    /// The number of spans that were looked at
+    pub total: usize,
+}
+
+impl fmt::Display for ZeroDeltaWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} of {} spans took 0ns; they are probably shorter than the clock's resolution \
+                   (see `clock_resolution`), so their timings are misleading", self.zero_delta, self.total)
+    }
+}
+
+/// Checks whether more than a quarter of `spans` (and their
+/// descendants) have a `delta` of 0.
+///
+/// That usually means that the work being measured is faster than the
+/// clock can resolve.  The text dump prints this warning at the end.
+pub fn zero_delta_warning(spans: &[Span]) -> Option<ZeroDeltaWarning> {
+    fn count(spans: &[Span], warning: &mut ZeroDeltaWarning) {
+        for span in spans {
+            warning.total += 1;
+            if span.delta == 0 {
+                warning.zero_delta += 1;
+            }
+            count(&span.children, warning);
+        }
+    }
+
+    let mut warning = ZeroDeltaWarning { zero_delta: 0, total: 0 };
+    count(spans, &mut warning);
+    if warning.zero_delta * 4 > warning.total {
+        Some(warning)
+    } else {
+        None
+    }
+}
+
 pub fn dump_stdout() {
     let stdout = ::std::io::stdout();
     let stdout = stdout.lock();
     dump_text_to_writer(stdout);
 }
 
+/// The version of the format written by `dump_json`.
+///
+/// This has to be bumped whenever a field is added to `Thread`, `Span`
+/// or `Note` (or anything else about the format changes), so that
+/// readers can tell what they're reading.
+#[cfg(feature="json")]
+pub const JSON_VERSION: u32 = 2;
+
+/// Writes every thread's spans as JSON, in an object of the form
+/// `{ "version": JSON_VERSION, "threads": [...] }`.
 #[cfg(feature="json")]
 pub fn dump_json<W: std::io::Write>(out: &mut W) -> std::io::Result<()> {
-    out.write_all(serde_json::to_string_pretty(&threads()).unwrap().as_bytes())
+    dump_json_with_options(out, &JsonOptions::default())
+}
+
+/// Options for `dump_json_with_options`.
+#[cfg(feature="json")]
+#[derive(Debug, Clone)]
+pub struct JsonOptions {
+    /// Shift every thread's timestamps so that its first span starts
+    /// at 0 (see `rebase_to_zero`).
+    pub normalize_timestamps: bool,
+    /// What every timestamp and duration is multiplied by when it is
+    /// written, e.g. `1e-6` to write milliseconds instead of
+    /// nanoseconds.  Unless this is 1 (the default), they are written
+    /// as floats.
+    pub time_scale: f64,
+}
+
+#[cfg(feature="json")]
+impl Default for JsonOptions {
+    fn default() -> JsonOptions {
+        JsonOptions {
+            normalize_timestamps: false,
+            time_scale: 1.0,
+        }
+    }
+}
+
+#[cfg(feature="json")]
+pub fn dump_json_with_options<W: std::io::Write>(out: &mut W, options: &JsonOptions) -> std::io::Result<()> {
+    /// The fields of threads, spans and notes that hold nanoseconds
+    const TIME_FIELDS: &[&str] = &["start_ns", "end_ns", "delta", "instant", "inclusive_ns", "exclusive_ns", "epoch_offset_ns"];
+
+    fn scale(value: &mut serde_json::Value, time_scale: f64) {
+        match *value {
+            serde_json::Value::Array(ref mut values) => {
+                for value in values {
+                    scale(value, time_scale);
+                }
+            }
+            serde_json::Value::Object(ref mut fields) => {
+                for (key, value) in fields.iter_mut() {
+                    match value.as_u64() {
+                        Some(ns) if TIME_FIELDS.contains(&key.as_str()) => *value = (ns as f64 * time_scale).into(),
+                        _ => scale(value, time_scale),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    #[derive(Serialize)]
+    struct Versioned<'a> {
+        version: u32,
+        threads: &'a [Thread],
+    }
+
+    let mut threads = threads();
+    if options.normalize_timestamps {
+        rebase_to_zero(&mut threads);
+    }
+    let versioned = Versioned { version: JSON_VERSION, threads: &threads };
+    if options.time_scale == 1.0 {
+        return out.write_all(serde_json::to_string_pretty(&versioned).unwrap().as_bytes());
+    }
+
+    // The scaling is done on the way out, so the recorded data is untouched
+    let mut value = serde_json::to_value(&versioned).unwrap();
+    scale(&mut value, options.time_scale);
+    out.write_all(serde_json::to_string_pretty(&value).unwrap().as_bytes())
+}
+
+/// Writes the raw events that every thread recorded, before they are
+/// assembled into a tree of `Span`s, as JSON.
+///
+/// Each thread is written as an object with its `id`, `name` and a flat
+/// array of `events`, each of which has an `id`, the `parent` id (if
+/// any), `name`, `start_ns`, `end_ns` and `delta` (both `null` if the
+/// span is still running) and `notes`.  Unlike `dump_json`, this keeps
+/// the parent ids that were recorded, and spans that never ended.
+/// Spans recorded in aggregate-only mode or moved into a ring buffer
+/// aren't raw events anymore and are left out.
+#[cfg(feature="json")]
+pub fn dump_raw_events_json<W: std::io::Write>(out: &mut W) -> std::io::Result<()> {
+    #[derive(Serialize)]
+    struct RawThread<'a> {
+        id: usize,
+        name: Option<&'a str>,
+        events: &'a [Event],
+    }
+
+    let current = ::std::thread::current();
+    let mut threads = vec![with_library(|library| {
+        let thread = RawThread {
+            id: ::thread_id::get(),
+            name: current.name(),
+            events: &library.current.all,
+        };
+        serde_json::to_value(&thread).unwrap()
+    })];
+
+    if let Ok(handle) = ALL_THREADS.lock() {
+        for &(id, ref name, ref frm) in &*handle {
+            let thread = RawThread { id, name: name.as_deref(), events: &frm.all };
+            threads.push(serde_json::to_value(&thread).unwrap());
+        }
+    }
+
+    out.write_all(serde_json::to_string_pretty(&threads).unwrap().as_bytes())
+}
+
+/// Reads back a list of threads that was written by `dump_json`.
+///
+/// Together with `dump_html_from_threads`, this makes it possible
+/// to render a report for a profile that was recorded in the past.
+/// Files written by a newer version of Flame than this one (see
+/// `JSON_VERSION`) are rejected, while the bare list of threads that
+/// older versions wrote is still accepted.
+#[cfg(feature="json")]
+pub fn read_threads_json<R: std::io::Read>(r: R) -> Result<Vec<Thread>, serde_json::Error> {
+    use serde::de::Error;
+
+    let mut value: serde_json::Value = serde_json::from_reader(r)?;
+    if value.is_array() {
+        return serde_json::from_value(value);
+    }
+
+    let version = value.get("version").and_then(|version| version.as_u64())
+        .ok_or_else(|| serde_json::Error::custom("missing version"))?;
+    if version > u64::from(JSON_VERSION) {
+        return Err(serde_json::Error::custom(format!(
+            "unsupported version {} (the newest supported version is {})", version, JSON_VERSION)));
+    }
+    match value.get_mut("threads") {
+        Some(threads) => serde_json::from_value(threads.take()),
+        None => Err(serde_json::Error::custom("missing threads")),
+    }
 }
 
-pub use html::{dump_html, dump_html_custom};
+pub use html::{dump_html, dump_html_custom, dump_html_from_threads, dump_html_show_gaps, dump_html_titled, HtmlOptions};
+pub use transform::{align_threads, annotate_times, collapse_below_depth, extract, merged_spans, prune_below, rebase_depth, rebase_to_zero, rollup_recursion, to_nested_set, unified_tree, NestedSetRow};
+pub use fixed::FixedSession;
+pub use folded::{dump_folded, dump_folded_to_writer, dump_folded_with_options, FoldedOptions};
+pub use dot::{dump_dot_to_writer, dump_dot_with_options, DotOptions};
+pub use csv::dump_csv_to_writer;
+pub use stats::{aggregate, budget_report, percentiles, BudgetViolation, SpanStats};
+#[cfg(feature = "digest")]
+pub use canonical::canonical_digest;
+#[cfg(feature = "async-tokio")]
+pub use async_tokio::with_task_profiling;
+#[cfg(feature = "skew-check")]
+pub use skew::{set_skew_tolerance, set_wall_clock};
+#[cfg(feature = "otel")]
+pub use otel::{to_otel_spans, write_otlp_json, OtelEvent, OtelSpan};
+#[cfg(feature = "testutil")]
+pub use testutil::expect_span;
+#[cfg(feature = "rss")]
+pub use rss::Rss;
 
 // ======================= flamescope ===============================
 
-mod flamescope {
+pub mod flamescope {
 
 use super::Span;
 use super::StrCow;
+use super::Thread;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -660,16 +3115,40 @@ pub enum ValueUnit {
     Seconds,
 }
 
-extern crate indexmap;
-
-use self::indexmap::IndexSet;
+use indexmap::IndexSet;
+use serde::ser::{Serialize, SerializeSeq, SerializeStruct, Serializer};
 
+use std::cell::RefCell;
 use std::io::Write;
 
 const JSON_SCHEMA_URL: &str = "https://www.speedscope.app/file-format-schema.json";
 
+/// Metadata to include in an exported speedscope file.
+#[derive(Debug, Clone, Default)]
+pub struct SpeedscopeOptions {
+    /// The program that exported the file, e.g. "flame 0.2"
+    pub exporter: Option<String>,
+    /// The name of the file, shown in speedscope's UI
+    pub name: Option<String>,
+    /// The index of the profile that speedscope should show first
+    pub active_profile_index: Option<u64>,
+    /// Collapse every span that is this deep into a single frame
+    /// (see `collapse_below_depth`)
+    pub collapse_below_depth: Option<u16>,
+}
+
 /// Convert flame spans to the speedscope profile format.
 pub fn spans_to_speedscope(spans: Vec<Span>) -> SpeedscopeFile {
+    spans_to_speedscope_with(spans, SpeedscopeOptions::default())
+}
+
+/// Convert flame spans to the speedscope profile format,
+/// including the given metadata.
+pub fn spans_to_speedscope_with(spans: Vec<Span>, options: SpeedscopeOptions) -> SpeedscopeFile {
+    let spans = match options.collapse_below_depth {
+        Some(max_depth) => super::collapse_below_depth(spans, max_depth),
+        None => spans,
+    };
     let mut frames = IndexSet::new();
     let profiles = spans
         .into_iter()
@@ -688,9 +3167,59 @@ pub fn spans_to_speedscope(spans: Vec<Span>) -> SpeedscopeFile {
     SpeedscopeFile {
         // always the same
         schema: JSON_SCHEMA_URL,
-        active_profile_index: None,
+        active_profile_index: options.active_profile_index,
+        exporter: options.exporter,
+        name: options.name,
+        profiles,
+        shared: Shared {
+            frames: frames.into_iter().collect(),
+        },
+    }
+}
+
+/// Convert the spans of several threads to the speedscope profile format.
+///
+/// Each thread that recorded anything gets its own profile, named
+/// after the thread, so they can be told apart in speedscope.  The
+/// threads are put on a common timeline with `align_threads`, and
+/// speedscope opens on the thread that spent the most time in spans.
+pub fn threads_to_speedscope(threads: &[Thread]) -> SpeedscopeFile {
+    let mut threads = threads.to_vec();
+    super::align_threads(&mut threads);
+
+    let mut frames = IndexSet::new();
+    let mut names = vec![];
+    let mut totals: Vec<u64> = vec![];
+    let profiles = threads
+        .iter()
+        .filter(|thread| !thread.spans.is_empty())
+        .map(|thread| {
+            let name = thread.label();
+            names.push(name.clone());
+            totals.push(thread.spans.iter().map(|s| s.delta).sum());
+            Profile::Evented {
+                name: name.into(),
+                unit: ValueUnit::Nanoseconds,
+                start_value: thread.spans.iter().map(|s| s.start_ns).min().unwrap_or(0),
+                end_value: thread.spans.iter().map(|s| s.end_ns).max().unwrap_or(0),
+                events: {
+                    let mut events = Vec::new();
+                    for span in &thread.spans {
+                        span_extend_events(&mut frames, &mut events, span.clone());
+                    }
+                    events
+                },
+            }
+        })
+        .collect();
+    // The first of the busiest threads, if there's a tie
+    let busiest = totals.iter().enumerate().rev().max_by_key(|&(_, total)| total).map(|(i, _)| i as u64);
+    SpeedscopeFile {
+        // always the same
+        schema: JSON_SCHEMA_URL,
+        active_profile_index: busiest,
         exporter: None,
-        name: None,
+        name: Some(names.join(", ")),
         profiles,
         shared: Shared {
             frames: frames.into_iter().collect(),
@@ -720,9 +3249,126 @@ pub fn dump(writer: impl Write) -> serde_json::Result<()> {
     write_spans(writer, super::spans())
 }
 
-#[inline]
+/// Write flame spans to `writer` in the speedscope profile format.
+///
+/// Unlike serializing the result of `spans_to_speedscope`, the events
+/// are streamed straight to the writer as the span tree is walked, so
+/// only the table of distinct frames is kept in memory.  The output is
+/// identical either way.
 pub fn write_spans(writer: impl Write, spans: Vec<Span>) -> serde_json::Result<()> {
-    let speedscope = spans_to_speedscope(spans);
+    let profiles = spans
+        .iter()
+        .map(|span| StreamedProfile {
+            name: &span.name,
+            start_value: span.start_ns,
+            end_value: span.end_ns,
+            spans: ::std::slice::from_ref(span),
+        })
+        .collect();
+    let file = StreamedFile {
+        profiles,
+        frames: RefCell::new(IndexSet::new()),
+    };
+    serde_json::to_writer(writer, &file)
+}
+
+// The streaming counterparts of `SpeedscopeFile` and `Profile`.  Fields
+// are emitted in the same order as the derived implementations, which
+// puts `shared` after `profiles`, so frames can be interned while the
+// events are written.
+struct StreamedFile<'a> {
+    profiles: Vec<StreamedProfile<'a>>,
+    frames: RefCell<IndexSet<Frame>>,
+}
+
+struct StreamedProfile<'a> {
+    name: &'a str,
+    start_value: u64,
+    end_value: u64,
+    spans: &'a [Span],
+}
+
+struct StreamedProfiles<'a>(&'a StreamedFile<'a>);
+
+struct StreamedEvents<'a> {
+    profile: &'a StreamedProfile<'a>,
+    frames: &'a RefCell<IndexSet<Frame>>,
+}
+
+impl<'a> Serialize for StreamedFile<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("SpeedscopeFile", 6)?;
+        state.serialize_field("$schema", JSON_SCHEMA_URL)?;
+        state.serialize_field("profiles", &StreamedProfiles(self))?;
+        let shared = Shared { frames: self.frames.borrow().iter().cloned().collect() };
+        state.serialize_field("shared", &shared)?;
+        state.serialize_field("activeProfileIndex", &None::<u64>)?;
+        state.serialize_field("exporter", &None::<String>)?;
+        state.serialize_field("name", &None::<String>)?;
+        state.end()
+    }
+}
+
+impl<'a> Serialize for StreamedProfiles<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0.profiles.len()))?;
+        for profile in &self.0.profiles {
+            seq.serialize_element(&StreamedEvents { profile, frames: &self.0.frames })?;
+        }
+        seq.end()
+    }
+}
+
+impl<'a> Serialize for StreamedEvents<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        struct Events<'a>(&'a StreamedEvents<'a>);
+
+        impl<'a> Serialize for Events<'a> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let mut seq = serializer.serialize_seq(None)?;
+                for span in self.0.profile.spans {
+                    stream_span_events(&mut seq, self.0.frames, span)?;
+                }
+                seq.end()
+            }
+        }
+
+        let mut state = serializer.serialize_struct("Profile", 6)?;
+        state.serialize_field("type", "evented")?;
+        state.serialize_field("name", self.profile.name)?;
+        state.serialize_field("unit", &ValueUnit::Nanoseconds)?;
+        state.serialize_field("startValue", &self.profile.start_value)?;
+        state.serialize_field("endValue", &self.profile.end_value)?;
+        state.serialize_field("events", &Events(self))?;
+        state.end()
+    }
+}
+
+fn stream_span_events<S: SerializeSeq>(seq: &mut S, frames: &RefCell<IndexSet<Frame>>, span: &Span) -> Result<(), S::Error> {
+    let (frame, _) = frames.borrow_mut().insert_full(Frame::new(span.name.clone()));
+    seq.serialize_element(&Event {
+        event_type: EventType::OpenFrame,
+        at: span.start_ns,
+        frame,
+    })?;
+    for child in &span.children {
+        stream_span_events(seq, frames, child)?;
+    }
+    seq.serialize_element(&Event {
+        event_type: EventType::CloseFrame,
+        at: span.end_ns,
+        frame,
+    })
+}
+
+#[inline]
+pub fn dump_threads(writer: impl Write) -> serde_json::Result<()> {
+    write_threads(writer, &super::threads())
+}
+
+#[inline]
+pub fn write_threads(writer: impl Write, threads: &[Thread]) -> serde_json::Result<()> {
+    let speedscope = threads_to_speedscope(threads);
     serde_json::to_writer(writer, &speedscope)
 }
 }
@@ -730,10 +3376,11 @@ pub fn write_spans(writer: impl Write, spans: Vec<Span>) -> serde_json::Result<(
 // ============================ FFI  ================================
 use std::ffi::CStr;
 use std::fs::File;
-use std::os::raw::c_char;
+use std::os::raw::{c_char, c_void};
 
 #[no_mangle]
-pub extern fn flame_start(name: *const c_char) {
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn flame_start(name: *const c_char) {
     let result = std::panic::catch_unwind(|| {
         let name = unsafe { CStr::from_ptr(name).to_str().unwrap().to_owned() };
         start(name); 
@@ -744,7 +3391,8 @@ pub extern fn flame_start(name: *const c_char) {
 }
 
 #[no_mangle]
-pub extern fn flame_end(name: *const c_char) {
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn flame_end(name: *const c_char) {
     let result = std::panic::catch_unwind(|| {
         let name = unsafe { CStr::from_ptr(name).to_str().unwrap().to_owned() };
         end(name); 
@@ -754,30 +3402,123 @@ pub extern fn flame_end(name: *const c_char) {
     }
 }
 
+/// The span behind a handle returned by `flame_scope_begin`.
+struct FfiScope {
+    name: String,
+    /// The id of the span, unless it wasn't recorded as an event
+    /// (e.g. because recording was suppressed)
+    id: Option<u32>,
+}
+
+/// Starts a span and returns an opaque handle to it, which must be
+/// passed to `flame_scope_end` exactly once.  Returns null on failure.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn flame_scope_begin(name: *const c_char) -> *mut c_void {
+    let result = std::panic::catch_unwind(|| {
+        let name = unsafe { CStr::from_ptr(name).to_str().unwrap().to_owned() };
+        let next_id = with_library(|library| library.current.next_id);
+        start(name.clone());
+        let id = with_library(|library| library.current.id_stack.last().cloned())
+            .filter(|&id| id == next_id);
+        Box::into_raw(Box::new(FfiScope { name, id })) as *mut c_void
+    });
+    match result {
+        Ok(handle) => handle,
+        Err(_) => {
+            eprintln!("error: rust panicked");
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Ends the span behind a handle returned by `flame_scope_begin`,
+/// and frees the handle.
+///
+/// The span is found by its id rather than by its name, and it
+/// must be the innermost span that is running.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn flame_scope_end(handle: *mut c_void) {
+    if handle.is_null() {
+        return;
+    }
+    let scope = unsafe { Box::from_raw(handle as *mut FfiScope) };
+    let result = std::panic::catch_unwind(move || {
+        match scope.id {
+            Some(id) => with_library(|library| {
+                let epoch = library.epoch;
+                let collector = &mut library.current;
+                if collector.id_stack.last() != Some(&id) {
+                    panic!("flame_scope_end({}) called while another span is running!", &scope.name);
+                }
+                end_current(collector, id, false, epoch)
+            }),
+            None => end(scope.name),
+        };
+    });
+    if result.is_err() {
+        eprintln!("error: rust panicked");
+    }
+}
+
 #[no_mangle]
-pub extern fn flame_dump(path: *const c_char) {
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn flame_dump(path: *const c_char) {
     let path = unsafe { CStr::from_ptr(path).to_str().unwrap() };
     flamescope::dump(&mut File::create(path).unwrap()).unwrap();
 }
 
 #[no_mangle]
-pub extern fn flame_dump_html(path: *const c_char) {
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn flame_dump_html(path: *const c_char) {
     let path = unsafe { CStr::from_ptr(path).to_str().unwrap() };
     dump_html(&mut File::create(path).unwrap()).unwrap();
 }
 
 #[no_mangle]
-pub extern fn flame_debug() {
+pub extern "C" fn flame_debug() {
     debug();
 }
 
 #[no_mangle]
-pub extern fn flame_dump_stdout() {
+pub extern "C" fn flame_dump_stdout() {
     dump_stdout();
 }
 
 #[no_mangle]
-pub extern fn flame_clear() {
+pub extern "C" fn flame_clear() {
     clear();
 }
 
+/// Returns the number of spans that every thread has recorded,
+/// including the ones inside of other spans, or 0 on a panic.
+#[no_mangle]
+pub extern "C" fn flame_span_count() -> u64 {
+    fn count(spans: &[Span]) -> u64 {
+        spans.iter().map(|span| 1 + count(&span.children)).sum()
+    }
+
+    let result = std::panic::catch_unwind(|| {
+        threads().iter().map(|thread| count(&thread.spans)).sum()
+    });
+    result.unwrap_or_else(|_| {
+        eprintln!("error: rust panicked");
+        0
+    })
+}
+
+/// Returns the total number of nanoseconds spent in the top-level
+/// spans of every thread, or 0 on a panic.
+#[no_mangle]
+pub extern "C" fn flame_total_ns() -> u64 {
+    let result = std::panic::catch_unwind(|| {
+        threads().iter().flat_map(|thread| &thread.spans).map(|span| span.delta).sum()
+    });
+    result.unwrap_or_else(|_| {
+        eprintln!("error: rust panicked");
+        0
+    })
+}
+
+}
@@ -0,0 +1,153 @@
+use std::io::Write;
+use std::time::SystemTime;
+use super::{align_threads, duration_to_ns, Span, StrCow, Thread, PROCESS_EPOCH};
+
+/// A span in the shape that OpenTelemetry expects.  See `to_otel_spans`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OtelSpan {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    /// The `span_id` of the parent span, if this isn't a top-level span
+    pub parent_span_id: Option<[u8; 8]>,
+    pub name: StrCow,
+    pub start_time_unix_nano: u64,
+    pub end_time_unix_nano: u64,
+    /// The notes on the span
+    pub events: Vec<OtelEvent>,
+    /// The id of the thread that recorded the span
+    pub thread_id: usize,
+    /// The name of the thread that recorded the span
+    pub thread_name: Option<String>,
+}
+
+/// A note, as an event on an `OtelSpan`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OtelEvent {
+    pub name: StrCow,
+    pub description: Option<StrCow>,
+    pub time_unix_nano: u64,
+}
+
+/// Converts the spans of every thread into OpenTelemetry spans, so
+/// they can be sent to a distributed tracing backend.
+///
+/// All of the spans belong to one trace, whose id is made from the
+/// time the process started and its process id.  Span ids are made
+/// from the position of the thread and the order in which its spans
+/// are visited, and every span points at its parent's id.  The threads
+/// are put on a common timeline with `align_threads`, which is then
+/// turned into wall clock time.  Notes become span events.
+pub fn to_otel_spans(threads: &[Thread]) -> Vec<OtelSpan> {
+    fn convert(span: &Span, parent: Option<[u8; 8]>, ids: &mut (u32, u32), context: &Context, out: &mut Vec<OtelSpan>) {
+        ids.1 += 1;
+        let span_id = (u64::from(ids.0) << 32 | u64::from(ids.1)).to_be_bytes();
+        out.push(OtelSpan {
+            trace_id: context.trace_id,
+            span_id,
+            parent_span_id: parent,
+            name: span.name.clone(),
+            start_time_unix_nano: context.epoch_unix_ns + span.start_ns,
+            end_time_unix_nano: context.epoch_unix_ns + span.end_ns,
+            events: span.notes_sorted().into_iter().map(|note| OtelEvent {
+                name: note.name.clone(),
+                description: note.description.clone(),
+                time_unix_nano: context.epoch_unix_ns + note.instant,
+            }).collect(),
+            thread_id: context.thread_id,
+            thread_name: context.thread_name.clone(),
+        });
+        for child in &span.children {
+            convert(child, Some(span_id), ids, context, out);
+        }
+    }
+
+    struct Context {
+        trace_id: [u8; 16],
+        epoch_unix_ns: u64,
+        thread_id: usize,
+        thread_name: Option<String>,
+    }
+
+    let elapsed = duration_to_ns(PROCESS_EPOCH.elapsed());
+    let now_unix_ns = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(duration_to_ns)
+        .unwrap_or(0);
+    let epoch_unix_ns = now_unix_ns.saturating_sub(elapsed);
+
+    let mut trace_id = [0; 16];
+    trace_id[.. 8].copy_from_slice(&epoch_unix_ns.to_be_bytes());
+    trace_id[8 ..].copy_from_slice(&u64::from(::std::process::id()).to_be_bytes());
+
+    let mut threads = threads.to_vec();
+    align_threads(&mut threads);
+
+    let mut out = vec![];
+    for (index, thread) in threads.iter().enumerate() {
+        let context = Context {
+            trace_id,
+            epoch_unix_ns,
+            thread_id: thread.id,
+            thread_name: thread.name.clone(),
+        };
+        let mut ids = (index as u32 + 1, 0);
+        for span in &thread.spans {
+            convert(span, None, &mut ids, &context, &mut out);
+        }
+    }
+    out
+}
+
+/// Writes the spans of every thread as an OTLP/JSON trace export,
+/// which can be sent to an OpenTelemetry collector as-is.
+///
+/// See `to_otel_spans` for how the spans are converted.
+pub fn write_otlp_json<W: Write>(out: W, threads: &[Thread]) -> ::serde_json::Result<()> {
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn string_attribute(key: &str, value: &str) -> ::serde_json::Value {
+        ::serde_json::json!({ "key": key, "value": { "stringValue": value } })
+    }
+
+    let spans: Vec<::serde_json::Value> = to_otel_spans(threads).into_iter().map(|span| {
+        let mut attributes = vec![string_attribute("thread.id", &span.thread_id.to_string())];
+        if let Some(ref name) = span.thread_name {
+            attributes.push(string_attribute("thread.name", name));
+        }
+        let events: Vec<::serde_json::Value> = span.events.iter().map(|event| {
+            let attributes: Vec<_> = event.description.iter()
+                .map(|description| string_attribute("description", description))
+                .collect();
+            ::serde_json::json!({
+                "name": event.name,
+                "timeUnixNano": event.time_unix_nano.to_string(),
+                "attributes": attributes,
+            })
+        }).collect();
+        ::serde_json::json!({
+            "traceId": hex(&span.trace_id),
+            "spanId": hex(&span.span_id),
+            "parentSpanId": span.parent_span_id.as_ref().map(|id| hex(id)).unwrap_or_default(),
+            "name": span.name,
+            // SPAN_KIND_INTERNAL
+            "kind": 1,
+            "startTimeUnixNano": span.start_time_unix_nano.to_string(),
+            "endTimeUnixNano": span.end_time_unix_nano.to_string(),
+            "attributes": attributes,
+            "events": events,
+        })
+    }).collect();
+
+    let export = ::serde_json::json!({
+        "resourceSpans": [{
+            "resource": { "attributes": [string_attribute("service.name", "flame")] },
+            "scopeSpans": [{
+                "scope": { "name": "flame" },
+                "spans": spans,
+            }],
+        }],
+    });
+    ::serde_json::to_writer(out, &export)
+}
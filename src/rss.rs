@@ -0,0 +1,68 @@
+/// How much memory the process was using when a span started and
+/// ended, sampled from its resident set size (RSS).
+///
+/// The samples are only taken on Linux; elsewhere, and for spans that
+/// weren't recorded with `start` or `enter`, every field is `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub struct Rss {
+    /// The process' RSS in bytes when the span started
+    #[cfg_attr(feature = "json", serde(rename = "rss_start", default))]
+    pub start: Option<u64>,
+    /// The process' RSS in bytes when the span ended
+    #[cfg_attr(feature = "json", serde(rename = "rss_end", default))]
+    pub end: Option<u64>,
+    /// How much the RSS grew while the span was running.  This is
+    /// negative if memory was given back to the system.
+    #[cfg_attr(feature = "json", serde(rename = "rss_delta", default))]
+    pub delta: Option<i64>,
+}
+
+impl Rss {
+    /// Starts measuring from the current RSS.
+    pub(crate) fn started() -> Rss {
+        Rss { start: current(), end: None, delta: None }
+    }
+
+    /// Takes the sample at the end of the span, if there was one at
+    /// the start.
+    pub(crate) fn finish(&mut self) {
+        if let (Some(start), Some(end)) = (self.start, current()) {
+            self.end = Some(end);
+            self.delta = Some(end as i64 - start as i64);
+        }
+    }
+
+    /// Folds `other`, a span that was collapsed into this one, into
+    /// these samples.
+    pub(crate) fn merge(&mut self, other: &Rss) {
+        self.end = other.end.or(self.end);
+        self.delta = match (self.delta, other.delta) {
+            (Some(a), Some(b)) => Some(a + b),
+            (a, b) => a.or(b),
+        };
+    }
+}
+
+/// Returns the resident set size of the process in bytes, from the
+/// number of resident pages in `/proc/self/statm`.
+#[cfg(target_os = "linux")]
+fn current() -> Option<u64> {
+    extern "C" {
+        fn sysconf(name: ::std::os::raw::c_int) -> ::std::os::raw::c_long;
+    }
+    const _SC_PAGESIZE: ::std::os::raw::c_int = 30;
+
+    let statm = ::std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { sysconf(_SC_PAGESIZE) };
+    if page_size <= 0 {
+        return None;
+    }
+    Some(pages * page_size as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn current() -> Option<u64> {
+    None
+}
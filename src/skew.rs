@@ -0,0 +1,64 @@
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use super::duration_to_ns;
+
+static TOLERANCE_NS: AtomicU64 = AtomicU64::new(0);
+/// True while a wall clock has been installed with `set_wall_clock`,
+/// so that `SystemTime::now` can be read without taking the lock
+static CUSTOM_WALL_CLOCK: AtomicBool = AtomicBool::new(false);
+lazy_static!(static ref WALL_CLOCK: RwLock<fn() -> SystemTime> = RwLock::new(SystemTime::now););
+
+/// Turns on checking each span's timing against the wall clock.
+///
+/// When a span ends, the time that passed according to the monotonic
+/// clock is compared with the time that passed according to the wall
+/// clock, and if they differ by more than `tolerance` a `Warn` note
+/// named "clock skew" is attached to the span.  This flags spans whose
+/// timings are suspect, e.g. because the machine was suspended or the
+/// VM was migrated while they were running.
+///
+/// Pass `None` to turn checking off again.  Spans that were already
+/// running when checking was turned on aren't checked.
+pub fn set_skew_tolerance(tolerance: Option<Duration>) {
+    let tolerance_ns = tolerance.map(|tolerance| duration_to_ns(tolerance).max(1)).unwrap_or(0);
+    TOLERANCE_NS.store(tolerance_ns, Ordering::Relaxed);
+}
+
+/// Replaces the wall clock that spans are checked against, which is
+/// `SystemTime::now` by default.
+pub fn set_wall_clock(clock: fn() -> SystemTime) {
+    *WALL_CLOCK.write().unwrap_or_else(|e| e.into_inner()) = clock;
+    CUSTOM_WALL_CLOCK.store(true, Ordering::SeqCst);
+}
+
+/// Reads the wall clock, if skew checking is turned on.
+pub fn wall_now() -> Option<SystemTime> {
+    if TOLERANCE_NS.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    if !CUSTOM_WALL_CLOCK.load(Ordering::Relaxed) {
+        return Some(SystemTime::now());
+    }
+    let clock = *WALL_CLOCK.read().unwrap_or_else(|e| e.into_inner());
+    Some(clock())
+}
+
+/// Compares the `monotonic_ns` that a span took with the time that
+/// passed on the wall clock since `wall_start`, and describes the
+/// difference if it's larger than the tolerance.
+pub fn check(wall_start: SystemTime, monotonic_ns: u64) -> Option<String> {
+    let tolerance_ns = TOLERANCE_NS.load(Ordering::Relaxed);
+    let wall_end = wall_now()?;
+    let description = match wall_end.duration_since(wall_start) {
+        Ok(wall) => {
+            let wall_ns = duration_to_ns(wall);
+            if wall_ns.max(monotonic_ns) - wall_ns.min(monotonic_ns) <= tolerance_ns {
+                return None;
+            }
+            format!("monotonic clock measured {}ns, wall clock measured {}ns", monotonic_ns, wall_ns)
+        }
+        Err(_) => format!("monotonic clock measured {}ns, wall clock went backwards", monotonic_ns),
+    };
+    Some(description)
+}
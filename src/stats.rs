@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use indexmap::IndexMap;
+use super::{duration_to_ns, threads, Span, StrCow};
+
+/// Summary statistics for the spans with one name at one depth.
+/// See `aggregate`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpanStats {
+    pub name: StrCow,
+    pub depth: u16,
+    /// How many times the span ran
+    pub count: u64,
+    pub total_ns: u64,
+    pub min_ns: u64,
+    pub max_ns: u64,
+    pub mean_ns: u64,
+}
+
+/// Groups every span in the trees by its name and depth, and
+/// summarizes the durations of each group, in the order that the
+/// groups are first seen in (depth first).
+///
+/// A collapsed span counts as `collapse_count` runs that each took
+/// the average of its `delta`, since the individual durations are
+/// no longer known.
+pub fn aggregate(spans: &[Span]) -> Vec<SpanStats> {
+    fn visit(spans: &[Span], groups: &mut IndexMap<(StrCow, u16), SpanStats>) {
+        for span in spans {
+            let runs = span.collapse_count.max(1);
+            let each_ns = span.delta / runs;
+            let stats = groups.entry((span.name.clone(), span.depth)).or_insert_with(|| SpanStats {
+                name: span.name.clone(),
+                depth: span.depth,
+                count: 0,
+                total_ns: 0,
+                min_ns: u64::MAX,
+                max_ns: 0,
+                mean_ns: 0,
+            });
+            stats.count += runs;
+            stats.total_ns += span.delta;
+            stats.min_ns = stats.min_ns.min(each_ns);
+            stats.max_ns = stats.max_ns.max(each_ns);
+            visit(&span.children, groups);
+        }
+    }
+
+    let mut groups = IndexMap::new();
+    visit(spans, &mut groups);
+    groups.into_iter().map(|(_, mut stats)| {
+        stats.mean_ns = stats.total_ns / stats.count;
+        stats
+    }).collect()
+}
+
+/// Returns the duration, in nanoseconds, at each of the percentiles in
+/// `pcts` (from 0 to 100) of all the spans named `name` anywhere in
+/// the trees, e.g. `percentiles(&spans, "query", &[50.0, 99.0])` for
+/// the median and p99 query time.
+///
+/// Percentiles that fall between two durations are linearly
+/// interpolated.  If no span is named `name` the result is empty, and
+/// a collapsed span counts as `collapse_count` runs that each took the
+/// average of its `delta`, as in `aggregate`.
+pub fn percentiles(spans: &[Span], name: &str, pcts: &[f64]) -> Vec<(f64, u64)> {
+    fn collect(spans: &[Span], name: &str, deltas: &mut Vec<u64>) {
+        for span in spans {
+            if span.name == name {
+                let runs = span.collapse_count.max(1);
+                deltas.extend((0 .. runs).map(|_| span.delta / runs));
+            }
+            collect(&span.children, name, deltas);
+        }
+    }
+
+    let mut deltas = vec![];
+    collect(spans, name, &mut deltas);
+    if deltas.is_empty() {
+        return vec![];
+    }
+    deltas.sort_unstable();
+
+    pcts.iter().map(|&pct| {
+        let rank = pct.clamp(0.0, 100.0) / 100.0 * (deltas.len() - 1) as f64;
+        let below = deltas[rank.floor() as usize] as f64;
+        let above = deltas[rank.ceil() as usize] as f64;
+        (pct, (below + (above - below) * rank.fract()).round() as u64)
+    }).collect()
+}
+
+/// A span that took longer than its budget.  See `budget_report`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BudgetViolation {
+    pub name: StrCow,
+    /// The names of the span's ancestors, outermost first, followed
+    /// by its own name
+    pub path: Vec<StrCow>,
+    pub actual_ns: u64,
+    pub budget_ns: u64,
+    /// How much longer than its budget the span took
+    pub overage_ns: u64,
+}
+
+/// Returns every span, on any thread, that took longer than the budget
+/// given for its name in `budgets`.  Spans whose names aren't in
+/// `budgets` are never reported.
+///
+/// This is meant to be called at the end of a run, e.g. to fail a CI
+/// job when some operation got too slow.
+pub fn budget_report(budgets: &HashMap<StrCow, Duration>) -> Vec<BudgetViolation> {
+    fn visit(spans: &[Span], budgets: &HashMap<StrCow, u64>, path: &mut Vec<StrCow>, out: &mut Vec<BudgetViolation>) {
+        for span in spans {
+            path.push(span.name.clone());
+            if let Some(&budget_ns) = budgets.get(&span.name) {
+                if span.delta > budget_ns {
+                    out.push(BudgetViolation {
+                        name: span.name.clone(),
+                        path: path.clone(),
+                        actual_ns: span.delta,
+                        budget_ns,
+                        overage_ns: span.delta - budget_ns,
+                    });
+                }
+            }
+            visit(&span.children, budgets, path, out);
+            path.pop();
+        }
+    }
+
+    let budgets = budgets.iter().map(|(name, &budget)| (name.clone(), duration_to_ns(budget))).collect();
+    let mut out = vec![];
+    for thread in threads() {
+        visit(&thread.spans, &budgets, &mut vec![], &mut out);
+    }
+    out
+}
@@ -0,0 +1,29 @@
+use super::Span;
+
+/// Finds the span at the end of `path`, a list of span names starting
+/// at the top level, or panics with a message that says where the
+/// path stopped matching and what could have matched instead.
+///
+/// When several siblings share a name, the first one is used.
+pub fn expect_span<'a>(spans: &'a [Span], path: &[&str]) -> &'a Span {
+    fn names(spans: &[Span]) -> Vec<&str> {
+        spans.iter().map(|span| &span.name[..]).collect()
+    }
+
+    if path.is_empty() {
+        panic!("expect_span called with an empty path");
+    }
+
+    let mut current: Option<&Span> = None;
+    for name in path {
+        let candidates = current.map(|span| &span.children[..]).unwrap_or(spans);
+        match candidates.iter().find(|span| span.name == *name) {
+            Some(span) => current = Some(span),
+            None => match current {
+                Some(parent) => panic!("no child {:?} under {:?}; available: {:?}", name, parent.name, names(candidates)),
+                None => panic!("no top-level span {:?}; available: {:?}", name, names(candidates)),
+            },
+        }
+    }
+    current.unwrap()
+}
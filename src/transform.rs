@@ -0,0 +1,400 @@
+use super::{Span, Note, StrCow, Thread};
+
+/// Collapses every span at or below `max_depth` into a single block.
+///
+/// Spans that are `max_depth` deep keep their full `delta` (which
+/// already includes the time of everything inside of them), but lose
+/// all of their descendants.  Notes recorded on the descendants are
+/// moved up onto the remaining span, and the descendants are added to
+/// its `collapse_count`, so `is_collapsed` is true for every span that
+/// had something inside of it.  Unlike removing the deep spans
+/// entirely, this keeps the total time of the tree intact.
+///
+/// This is useful for keeping huge trees readable.  The exporters can
+/// do it by themselves; see `HtmlOptions`, `FoldedOptions`,
+/// `SpeedscopeOptions`, `ChromeTraceOptions` and `DotOptions`.
+pub fn collapse_below_depth(spans: Vec<Span>, max_depth: u16) -> Vec<Span> {
+    /// Removes everything under `span`, moving the notes into `into`,
+    /// and returns how many spans were merged into the removed ones.
+    fn take_descendants(span: &mut Span, into: &mut Vec<Note>) -> u64 {
+        let mut count = 0;
+        for mut child in span.children.drain(..) {
+            into.append(&mut child.notes);
+            count += child.collapse_count + take_descendants(&mut child, into);
+        }
+        count
+    }
+
+    spans.into_iter().map(|mut span| {
+        if span.depth >= max_depth {
+            let mut notes = vec![];
+            span.collapse_count += take_descendants(&mut span, &mut notes);
+            if !notes.is_empty() {
+                span.notes.append(&mut notes);
+                span.notes.sort_by_key(|note| (note.instant, note.seq));
+            }
+        } else {
+            let children = ::std::mem::take(&mut span.children);
+            span.children = collapse_below_depth(children, max_depth);
+        }
+        span
+    }).collect()
+}
+
+/// Removes every span that took less than `min_ns`, unless something
+/// inside of it is kept.
+///
+/// A removed span's time stays in its parent's `delta`, where it
+/// becomes part of the parent's self time, and its notes (and those of
+/// everything inside of it) are moved up onto the parent.  Removed
+/// top-level spans are dropped along with their notes.
+///
+/// This cuts trees full of tiny spans down to the ones that matter
+/// before they're passed to one of the exporters.
+pub fn prune_below(spans: Vec<Span>, min_ns: u64) -> Vec<Span> {
+    fn prune(spans: Vec<Span>, min_ns: u64, pruned_notes: &mut Vec<Note>) -> Vec<Span> {
+        let mut kept = vec![];
+        for mut span in spans {
+            let children = ::std::mem::take(&mut span.children);
+            let mut notes = vec![];
+            span.children = prune(children, min_ns, &mut notes);
+            if !notes.is_empty() {
+                span.notes.append(&mut notes);
+                span.notes.sort_by_key(|note| (note.instant, note.seq));
+            }
+
+            if span.delta < min_ns && span.children.is_empty() {
+                pruned_notes.append(&mut span.notes);
+            } else {
+                kept.push(span);
+            }
+        }
+        kept
+    }
+
+    prune(spans, min_ns, &mut vec![])
+}
+
+/// Puts every thread on the same timeline by adding each thread's
+/// `epoch_offset_ns` to all of its timestamps (and then setting the
+/// offset to 0, so aligning twice does nothing).
+pub fn align_threads(threads: &mut [Thread]) {
+    for thread in threads {
+        let offset = thread.epoch_offset_ns;
+        for span in &mut thread.spans {
+            shift(span, offset as i64);
+        }
+        thread.epoch_offset_ns = 0;
+    }
+}
+
+/// Shifts the timestamps of every thread so that its earliest span
+/// starts at 0.
+///
+/// The `start_ns` and `end_ns` of every span, and the `instant` of
+/// every note, have the thread's earliest `start_ns` subtracted from
+/// them.  Durations are left untouched.
+pub fn rebase_to_zero(threads: &mut [Thread]) {
+    for thread in threads {
+        let earliest = thread.spans.iter().map(|span| span.start_ns).min().unwrap_or(0);
+        for span in &mut thread.spans {
+            shift(span, -(earliest as i64));
+        }
+    }
+}
+
+/// Adds `by` to every timestamp in `span` and its children.
+fn shift(span: &mut Span, by: i64) {
+    fn add(timestamp: u64, by: i64) -> u64 {
+        if by < 0 {
+            timestamp.saturating_sub(by.unsigned_abs())
+        } else {
+            timestamp + by as u64
+        }
+    }
+
+    span.start_ns = add(span.start_ns, by);
+    span.end_ns = add(span.end_ns, by);
+    for note in &mut span.notes {
+        note.instant = add(note.instant, by);
+    }
+    for child in &mut span.children {
+        shift(child, by);
+    }
+}
+
+/// One span in the nested-set representation produced by `to_nested_set`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub struct NestedSetRow {
+    pub name: StrCow,
+    pub depth: u16,
+    /// The left bound; every descendant's bounds lie between `lft` and `rgt`
+    pub lft: u64,
+    /// The right bound
+    pub rgt: u64,
+    pub delta_ns: u64,
+}
+
+/// Flattens a span tree into nested-set rows, for loading into a
+/// relational database.
+///
+/// A depth-first walk numbers each span when it's entered (`lft`) and
+/// when it's left (`rgt`), so a span is a descendant of another exactly
+/// when its bounds lie inside of the other's.  This turns ancestor and
+/// descendant lookups into simple range queries.  Rows are returned in
+/// the order they were entered.
+pub fn to_nested_set(spans: &[Span]) -> Vec<NestedSetRow> {
+    fn visit(span: &Span, counter: &mut u64, out: &mut Vec<NestedSetRow>) {
+        let index = out.len();
+        out.push(NestedSetRow {
+            name: span.name.clone(),
+            depth: span.depth,
+            lft: *counter,
+            rgt: 0,
+            delta_ns: span.delta,
+        });
+        *counter += 1;
+        for child in &span.children {
+            visit(child, counter, out);
+        }
+        out[index].rgt = *counter;
+        *counter += 1;
+    }
+
+    let mut out = vec![];
+    let mut counter = 1;
+    for span in spans {
+        visit(span, &mut counter, &mut out);
+    }
+    out
+}
+
+/// Combines the spans of every thread into a single tree.
+///
+/// The root is a synthetic span named `root_name`, with one synthetic
+/// child per thread that recorded anything, named after the thread.
+/// Each of those contains the thread's spans, so the depth of every
+/// recorded span goes up by 2.  Threads are put on a common timeline
+/// with `align_threads` first, and each synthetic span covers
+/// everything inside of it.
+pub fn unified_tree(root_name: &str) -> Span {
+    fn deepen(span: &mut Span, by: u16) {
+        span.depth = span.depth.saturating_add(by);
+        for child in &mut span.children {
+            deepen(child, by);
+        }
+    }
+
+    fn synthetic(name: StrCow, depth: u16, children: Vec<Span>) -> Span {
+        let start_ns = children.iter().map(|span| span.start_ns).min().unwrap_or(0);
+        let end_ns = children.iter().map(|span| span.end_ns).max().unwrap_or(0);
+        Span {
+            name,
+            start_ns,
+            end_ns,
+            delta: end_ns - start_ns,
+            depth,
+            children,
+            notes: vec![],
+            attributes: vec![],
+            metrics: Default::default(),
+            collapse_count: 1,
+            wait_reason: None,
+            annotation: false,
+            #[cfg(feature = "rss")]
+            rss: Default::default(),
+            inclusive_ns: None,
+            exclusive_ns: None,
+            collapsable: false,
+            _priv: (),
+        }
+    }
+
+    let mut threads = super::threads();
+    align_threads(&mut threads);
+
+    let children = threads.into_iter()
+        .filter(|thread| !thread.spans.is_empty())
+        .map(|mut thread| {
+            for span in &mut thread.spans {
+                deepen(span, 2);
+            }
+            synthetic(thread.label().into(), 1, thread.spans)
+        })
+        .collect();
+    synthetic(root_name.to_owned().into(), 0, children)
+}
+
+/// Folds recursion so that each name appears at most once on any
+/// path from the root, like flamegraph.pl does for recursive stacks.
+///
+/// A span that has an ancestor with the same name is merged into the
+/// nearest such ancestor: its self-time (and notes) are added to the
+/// ancestor, and its children move up to become children of the
+/// ancestor.  Children that move up are merged with a child of the
+/// ancestor that has the same name, if there is one, and the merged
+/// spans count towards its `collapse_count`.  Deltas are recomputed
+/// so that each span's `delta` still includes the time of everything
+/// inside of it.
+pub fn rollup_recursion(spans: Vec<Span>) -> Vec<Span> {
+    struct Node {
+        span: Span,
+        self_ns: u64,
+        children: Vec<usize>,
+    }
+
+    /// Places `span` inside of the last node in `chain`, or at the
+    /// top level if `chain` is empty.  `lifted` is true if the span
+    /// was moved up from inside of a span that was merged away.
+    fn place(mut span: Span, chain: &[usize], lifted: bool, nodes: &mut Vec<Node>, roots: &mut Vec<usize>) {
+        let children = ::std::mem::take(&mut span.children);
+        let self_ns = span.delta.saturating_sub(children.iter().map(|child| child.delta).sum());
+
+        let recursive = chain.iter().rposition(|&id| nodes[id].span.name == span.name);
+        let sibling = if lifted {
+            let siblings = chain.last().map(|&parent| &nodes[parent].children).unwrap_or(roots);
+            siblings.iter().cloned().find(|&id| nodes[id].span.name == span.name)
+        } else {
+            None
+        };
+
+        let (target, chain) = match (recursive, sibling) {
+            (Some(position), _) => (chain[position], chain[..=position].to_vec()),
+            (None, Some(sibling)) => (sibling, chain.iter().cloned().chain(Some(sibling)).collect()),
+            (None, None) => {
+                let id = nodes.len();
+                nodes.push(Node { span, self_ns, children: vec![] });
+                match chain.last() {
+                    Some(&parent) => nodes[parent].children.push(id),
+                    None => roots.push(id),
+                }
+                let chain: Vec<usize> = chain.iter().cloned().chain(Some(id)).collect();
+                for child in children {
+                    place(child, &chain, false, nodes, roots);
+                }
+                return;
+            }
+        };
+
+        let node = &mut nodes[target];
+        node.self_ns += self_ns;
+        node.span.collapse_count += span.collapse_count;
+        node.span.start_ns = node.span.start_ns.min(span.start_ns);
+        node.span.end_ns = node.span.end_ns.max(span.end_ns);
+        node.span.notes.append(&mut span.notes);
+        for child in children {
+            place(child, &chain, true, nodes, roots);
+        }
+    }
+
+    fn build(id: usize, depth: u16, nodes: &mut [Option<Node>]) -> Span {
+        // Every node is reachable from exactly one root.
+        let Node { mut span, self_ns, children } = nodes[id].take().unwrap();
+        let mut children: Vec<Span> = children.into_iter().map(|child| build(child, depth.saturating_add(1), nodes)).collect();
+        children.sort_by_key(|child| child.start_ns);
+
+        span.delta = self_ns + children.iter().map(|child| child.delta).sum::<u64>();
+        span.depth = depth;
+        span.notes.sort_by_key(|note| (note.instant, note.seq));
+        span.children = children;
+        span
+    }
+
+    let mut nodes = vec![];
+    let mut roots = vec![];
+    for span in spans {
+        place(span, &[], false, &mut nodes, &mut roots);
+    }
+    let mut nodes: Vec<Option<Node>> = nodes.into_iter().map(Some).collect();
+    roots.into_iter().map(|root| build(root, 0, &mut nodes)).collect()
+}
+
+/// Sets the depth of `span` to `depth`, and the depths of its
+/// descendants to match.
+pub fn rebase_depth(span: &mut Span, depth: u16) {
+    span.depth = depth;
+    for child in &mut span.children {
+        rebase_depth(child, depth.saturating_add(1));
+    }
+}
+
+/// Returns a copy of the first span named `name` (searching depth
+/// first, in order), with everything inside of it.
+///
+/// The copy is rebased to a depth of 0, so it can be kept, archived or
+/// rendered on its own after `spans` is gone.
+pub fn extract(spans: &[Span], name: &str) -> Option<Span> {
+    fn find<'a>(spans: &'a [Span], name: &str) -> Option<&'a Span> {
+        spans.iter().find_map(|span| {
+            if span.name == name {
+                Some(span)
+            } else {
+                find(&span.children, name)
+            }
+        })
+    }
+
+    find(spans, name).map(|span| {
+        let mut span = span.clone();
+        rebase_depth(&mut span, 0);
+        span
+    })
+}
+
+/// Fills in `inclusive_ns` and `exclusive_ns` on every span in the
+/// trees, so that renderers can read them instead of summing up
+/// children themselves.
+///
+/// The inclusive time is the span's `delta`; the exclusive time is
+/// that minus the `delta`s of its children.
+pub fn annotate_times(spans: &mut [Span]) {
+    for span in spans {
+        annotate_times(&mut span.children);
+        span.inclusive_ns = Some(span.delta);
+        span.exclusive_ns = Some(span.self_ns());
+    }
+}
+
+/// Merges the span trees of every thread into a single tree, for when
+/// several threads run the same code and their trees only differ in
+/// timing.
+///
+/// Spans are matched up by their path from the root: spans with the
+/// same name under matching parents (including siblings within a
+/// thread) become one span whose `delta` and `collapse_count` are the
+/// sums of theirs, and whose notes and children are the union of
+/// theirs, merged the same way.  Threads are put on a common timeline
+/// with `align_threads` first, and each merged span runs from the
+/// earliest start to the latest end.
+pub fn merged_spans() -> Vec<Span> {
+    fn merge(spans: Vec<Span>) -> Vec<Span> {
+        let mut merged: Vec<Span> = vec![];
+        for mut span in spans {
+            match merged.iter_mut().find(|other| other.name == span.name) {
+                Some(other) => {
+                    other.start_ns = other.start_ns.min(span.start_ns);
+                    other.end_ns = other.end_ns.max(span.end_ns);
+                    other.delta += span.delta;
+                    other.collapse_count += span.collapse_count;
+                    other.notes.append(&mut span.notes);
+                    other.children.append(&mut span.children);
+                    #[cfg(feature = "rss")]
+                    other.rss.merge(&span.rss);
+                }
+                None => merged.push(span),
+            }
+        }
+
+        for span in &mut merged {
+            let children = ::std::mem::take(&mut span.children);
+            span.children = merge(children);
+            span.notes.sort_by_key(|note| (note.instant, note.seq));
+        }
+        merged
+    }
+
+    let mut threads = super::threads();
+    align_threads(&mut threads);
+    merge(threads.into_iter().flat_map(|thread| thread.spans).collect())
+}
@@ -0,0 +1,55 @@
+#![cfg(feature = "async-tokio")]
+
+extern crate flame;
+extern crate tokio;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Starts a span, yields back to the runtime a few times while
+/// recording inside of it, and finally ends it.
+struct Yielding {
+    step: u32,
+}
+
+impl Future for Yielding {
+    type Output = Vec<flame::Span>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Vec<flame::Span>> {
+        match self.step {
+            0 => flame::start("task"),
+            1 ..= 3 => {
+                flame::start("poll");
+                flame::end("poll");
+            }
+            _ => {
+                flame::end("task");
+                return Poll::Ready(flame::spans());
+            }
+        }
+        self.step += 1;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[test]
+fn task_spans_stay_together() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .build()
+        .unwrap();
+
+    let handles: Vec<_> = (0 .. 8)
+        .map(|_| runtime.spawn(flame::with_task_profiling(Yielding { step: 0 })))
+        .collect();
+
+    for handle in handles {
+        let spans = runtime.block_on(handle).unwrap();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].name, "task");
+        assert_eq!(spans[0].children.len(), 3);
+        assert!(spans[0].children.iter().all(|c| c.name == "poll"));
+    }
+}
@@ -0,0 +1,33 @@
+#![cfg(feature = "micros")]
+
+extern crate flame;
+
+#[test]
+fn deltas_are_whole_microseconds() {
+    flame::clear();
+    flame::record_span("recorded", 1_500, 12_999, None);
+    let measured = flame::span_of("measured", || {
+        ::std::thread::sleep(::std::time::Duration::from_millis(2));
+        flame::spans()
+    });
+    drop(measured);
+
+    let spans = flame::spans();
+    assert_eq!(spans[0].start_ns, 1_000);
+    assert_eq!(spans[0].end_ns, 12_000);
+    assert_eq!(spans[0].delta, 11_000);
+    assert!(spans[1].delta >= 2_000_000);
+    assert_eq!(spans[1].delta % 1_000, 0);
+    assert_eq!(spans[1].end_ns - spans[1].start_ns, spans[1].delta);
+}
+
+#[test]
+// The memory samples taken with `rss` make events bigger again
+#[cfg(all(target_pointer_width = "64", not(feature = "rss")))]
+fn events_are_smaller() {
+    // A `FixedSession` stores its events inline, so the difference in
+    // size between two sessions is the size of the extra events.  With
+    // nanosecond timestamps every event takes up 128 bytes.
+    let per_event = (::std::mem::size_of::<flame::FixedSession<9>>() - ::std::mem::size_of::<flame::FixedSession<1>>()) / 8;
+    assert!(per_event <= 112, "{} bytes per event", per_event);
+}
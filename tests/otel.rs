@@ -0,0 +1,37 @@
+#![cfg(feature = "otel")]
+
+extern crate flame;
+extern crate serde_json;
+
+#[test]
+fn otel_parent_links() {
+    flame::clear();
+    flame::span_of("request", || {
+        flame::span_of("parse", || {});
+        flame::span_of("query", || {
+            flame::note("cache miss", None::<&str>);
+            flame::span_of("connect", || {});
+        });
+    });
+
+    let spans = flame::to_otel_spans(&flame::threads());
+    let by_name = |name: &str| spans.iter().find(|span| span.name == name).unwrap();
+    let request = by_name("request");
+    assert_eq!(request.parent_span_id, None);
+    assert_eq!(by_name("parse").parent_span_id, Some(request.span_id));
+    assert_eq!(by_name("query").parent_span_id, Some(request.span_id));
+    assert_eq!(by_name("connect").parent_span_id, Some(by_name("query").span_id));
+    assert_eq!(by_name("query").events[0].name, "cache miss");
+    assert!(spans.iter().all(|span| span.trace_id == request.trace_id));
+    assert!(request.start_time_unix_nano <= by_name("parse").start_time_unix_nano);
+
+    let mut out = vec![];
+    flame::write_otlp_json(&mut out, &flame::threads()).unwrap();
+    let export: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    let spans = export["resourceSpans"][0]["scopeSpans"][0]["spans"].as_array().unwrap();
+    let id_of = |name: &str| spans.iter().find(|span| span["name"] == name).unwrap()["spanId"].clone();
+    let connect = spans.iter().find(|span| span["name"] == "connect").unwrap();
+    assert_eq!(connect["parentSpanId"], id_of("query"));
+    assert_eq!(connect["spanId"].as_str().unwrap().len(), 16);
+    assert_eq!(connect["traceId"].as_str().unwrap().len(), 32);
+}
@@ -0,0 +1,24 @@
+#![cfg(all(feature = "rss", target_os = "linux"))]
+
+extern crate flame;
+extern crate serde_json;
+
+const SIZE: usize = 64 * 1024 * 1024;
+
+#[test]
+fn rss_delta_covers_allocation() {
+    flame::clear();
+    flame::start("allocate");
+    // Filled with ones so that every page is actually touched
+    let buffer = vec![1u8; SIZE];
+    ::std::mem::forget(buffer);
+    flame::end("allocate");
+
+    let span = &flame::spans()[0];
+    let delta = span.rss.delta.unwrap();
+    assert!(delta >= (SIZE as i64) * 9 / 10, "rss only grew by {}", delta);
+    assert_eq!(span.rss.end.unwrap() as i64 - span.rss.start.unwrap() as i64, delta);
+
+    let json = serde_json::to_value(span).unwrap();
+    assert_eq!(json["rss_delta"], delta);
+}
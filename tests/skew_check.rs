@@ -0,0 +1,44 @@
+#![cfg(feature = "skew-check")]
+
+extern crate flame;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static WALL_SECS: AtomicU64 = AtomicU64::new(1_000);
+
+/// A wall clock that only moves when the test says so.
+fn wall_clock() -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(WALL_SECS.load(Ordering::SeqCst))
+}
+
+#[test]
+fn skew_is_noted() {
+    flame::clear();
+    flame::set_wall_clock(wall_clock);
+    flame::set_skew_tolerance(Some(Duration::from_secs(1)));
+
+    flame::span_of("steady", || {});
+    flame::span_of("jumped", || {
+        // Pretend the machine was suspended for a minute.
+        WALL_SECS.fetch_add(60, Ordering::SeqCst);
+    });
+
+    // The same goes for spans ended by `end_all` and `end_by_id`.
+    flame::start("unclosed");
+    let explicit = flame::start_with_parent("explicit", None);
+    WALL_SECS.fetch_add(60, Ordering::SeqCst);
+    flame::end_all();
+    flame::end_by_id(explicit);
+
+    flame::set_skew_tolerance(None);
+    flame::set_wall_clock(SystemTime::now);
+
+    let spans = flame::spans();
+    assert!(spans[0].notes.is_empty());
+    assert_eq!(spans[1].notes.len(), 1);
+    assert_eq!(spans[1].notes[0].name, "clock skew");
+    assert_eq!(spans[1].notes[0].level, flame::NoteLevel::Warn);
+    assert_eq!(spans[2].notes[0].name, "clock skew");
+    assert_eq!(spans[3].notes[0].name, "clock skew");
+}
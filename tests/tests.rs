@@ -1,27 +1,72 @@
 extern crate flame;
+extern crate serde_json;
+
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Tests run side by side, but some of them change process-wide
+/// switches (`set_enabled`, `set_commit_sink`, ...) or look at what
+/// every thread has committed.  Those hold this lock exclusively, and
+/// all the others share it, so that the two kinds never overlap.
+static GLOBAL_STATE: RwLock<()> = RwLock::new(());
+
+/// Holds `GLOBAL_STATE` for one test, and throws away what the test
+/// thread recorded when it's done, so that the thread doesn't commit
+/// it on exit while another test is looking at every thread.
+struct Isolated<G> {
+    _guard: G,
+}
+
+impl<G> Drop for Isolated<G> {
+    fn drop(&mut self) {
+        flame::clear_current_thread();
+    }
+}
+
+fn shared() -> Isolated<RwLockReadGuard<'static, ()>> {
+    Isolated { _guard: GLOBAL_STATE.read().unwrap_or_else(|e| e.into_inner()) }
+}
+
+fn exclusive() -> Isolated<RwLockWriteGuard<'static, ()>> {
+    Isolated { _guard: GLOBAL_STATE.write().unwrap_or_else(|e| e.into_inner()) }
+}
 
 #[test]
 fn implicit_guarded_event() {
+    let _lock = shared();
     flame::clear();
-    flame::start_guard("foo");
+    let _ = flame::start_guard("foo");
 }
 
 #[test]
 fn named_guarded_event() {
+    let _lock = shared();
     flame::clear();
     let _name = flame::start_guard("foo");
 }
 
 #[test]
 fn dropped_guarded_event() {
+    let _lock = shared();
     flame::clear();
     let name = flame::start_guard("foo");
     name.end();
 }
 
+#[test]
+fn guard_end_returns_delta() {
+    let _lock = shared();
+    flame::clear();
+    let guard = flame::start_guard("foo");
+    ::std::thread::sleep(::std::time::Duration::from_millis(1));
+    let delta = guard.end();
+    assert!(delta >= 1_000_000);
+    assert_eq!(flame::spans()[0].delta, delta);
+}
+
 #[test]
 #[allow(unreachable_code)]
 fn multiple_guard_early_return() {
+    let _lock = shared();
     flame::clear();
     let _first = flame::start_guard("foo");
     let _second = flame::start_guard("bar");
@@ -29,6 +74,7 @@ fn multiple_guard_early_return() {
 
 #[test]
 fn single_event() {
+    let _lock = shared();
     flame::clear();
     flame::start("event1");
     flame::end("event1");
@@ -40,6 +86,7 @@ fn single_event() {
 
 #[test]
 fn single_nested() {
+    let _lock = shared();
     flame::clear();
     flame::start("event1");
         flame::start("event2");
@@ -59,6 +106,7 @@ fn single_nested() {
 
 #[test]
 fn double_nested() {
+    let _lock = shared();
     flame::clear();
     flame::start("event1");
         flame::start("event2");
@@ -82,6 +130,7 @@ fn double_nested() {
 
 #[test]
 fn threads() {
+    let _lock = exclusive();
     use std::thread::spawn;
     flame::clear();
     flame::start("main thread");
@@ -111,6 +160,7 @@ fn threads() {
 #[test]
 #[should_panic]
 fn wrong_name() {
+    let _lock = shared();
     flame::clear();
     flame::start("a");
     flame::end("b");
@@ -119,12 +169,14 @@ fn wrong_name() {
 #[test]
 #[should_panic]
 fn cant_note() {
+    let _lock = shared();
     flame::clear();
-    flame::note("hi", None);
+    flame::note("hi", None::<&str>);
 }
 
 #[test]
 fn end_with() {
+    let _lock = shared();
     fn _inner() -> u32 {
         flame::clear();
         flame::start("w");
@@ -132,3 +184,2423 @@ fn end_with() {
     }
     assert_eq!(1, _inner());
 }
+
+#[test]
+fn drain_all() {
+    let _lock = exclusive();
+    use std::thread::spawn;
+    flame::clear();
+    flame::start("main thread");
+    flame::end("main thread");
+
+    spawn(|| {
+        flame::start("worker");
+        flame::end("worker");
+        flame::commit_thread();
+    }).join().unwrap();
+    spawn(flame::register_thread).join().unwrap();
+
+    let drained = flame::drain_all();
+    assert_eq!(drained.len(), 2);
+    assert_eq!(drained[0].spans.len(), 1);
+    assert_eq!(drained[0].spans[0].name, "main thread");
+    assert_eq!(drained[1].spans.len(), 1);
+    assert_eq!(drained[1].spans[0].name, "worker");
+
+    let threads = flame::threads_include_empty();
+    assert_eq!(threads.len(), 1);
+    assert!(threads[0].spans.is_empty());
+}
+
+#[test]
+fn note_once() {
+    let _lock = shared();
+    flame::clear();
+    flame::start("retry");
+    for _ in 0 .. 3 {
+        flame::note_once("connection refused", None::<&str>);
+    }
+    flame::end("retry");
+
+    let spans = flame::spans();
+    assert_eq!(spans[0].notes.len(), 1);
+    assert_eq!(spans[0].notes[0].name, "connection refused");
+}
+
+#[test]
+fn json_round_trip_to_html() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::start("recorded earlier");
+    flame::start("child");
+    flame::end("child");
+    flame::end("recorded earlier");
+
+    let mut json = vec![];
+    flame::dump_json(&mut json).unwrap();
+
+    let threads = flame::read_threads_json(&json[..]).unwrap();
+    assert_eq!(threads[0].spans[0].name, "recorded earlier");
+    assert_eq!(threads[0].spans[0].children[0].name, "child");
+
+    let mut html = vec![];
+    flame::dump_html_from_threads(&mut html, &threads).unwrap();
+    let html = String::from_utf8(html).unwrap();
+    assert!(html.contains("\"recorded earlier\""));
+}
+
+#[test]
+fn checked_end_without_span() {
+    let _lock = shared();
+    use flame::FlameError;
+    flame::clear();
+    assert_eq!(flame::checked::end("a"), Err(FlameError::NoActiveSpan { name: "a".into() }));
+}
+
+#[test]
+fn checked_end_wrong_name() {
+    let _lock = shared();
+    use flame::FlameError;
+    flame::clear();
+    flame::checked::start("a").unwrap();
+    assert_eq!(flame::checked::end("b"),
+               Err(FlameError::NameMismatch { expected: "a".into(), found: "b".into(), suggestion: None }));
+
+    // The running span is left untouched
+    assert!(flame::checked::end("a").is_ok());
+    assert_eq!(flame::spans()[0].name, "a");
+}
+
+#[test]
+fn checked_note_without_span() {
+    let _lock = shared();
+    use flame::FlameError;
+    flame::clear();
+    assert_eq!(flame::checked::note("hi", None::<&str>), Err(FlameError::NoActiveSpan { name: "hi".into() }));
+}
+
+#[test]
+fn checked_span_of() {
+    let _lock = shared();
+    flame::clear();
+    assert_eq!(flame::checked::span_of("a", || 5), Ok(5));
+    assert_eq!(flame::spans()[0].name, "a");
+
+    let result = ::std::panic::catch_unwind(|| {
+        flame::checked::span_of("boom", || -> () { panic!("oh no") })
+    });
+    assert!(result.is_err());
+    assert_eq!(flame::spans()[1].name, "boom");
+    assert_eq!(flame::checked::span_of("after", || {}), Ok(()));
+    assert_eq!(flame::spans()[2].depth, 0);
+
+    // A span left running inside of it is ended along with it
+    let result = flame::checked::span_of("unbalanced", || flame::start("leaked"));
+    assert!(matches!(result, Err(flame::FlameError::NameMismatch { .. })));
+    assert!(flame::unclosed_spans().is_empty());
+    let unbalanced = &flame::spans()[3];
+    assert_eq!(unbalanced.name, "unbalanced");
+    assert_eq!(unbalanced.children[0].name, "leaked");
+}
+
+#[test]
+fn collapse_below_depth() {
+    let _lock = shared();
+    flame::clear();
+    flame::start("a");
+        flame::start("b");
+            flame::start("c");
+                flame::start("d");
+                flame::note("deep", None::<&str>);
+                flame::end("d");
+                flame::start("e");
+                flame::end("e");
+            flame::end("c");
+        flame::end("b");
+    flame::end("a");
+
+    let spans = flame::spans();
+    let c_delta = spans[0].children[0].children[0].delta;
+
+    let collapsed = flame::collapse_below_depth(spans, 2);
+    let c = &collapsed[0].children[0].children[0];
+    assert_eq!(c.name, "c");
+    assert_eq!(c.depth, 2);
+    assert!(c.children.is_empty());
+    assert_eq!(c.delta, c_delta);
+    assert_eq!(c.notes[0].name, "deep");
+    assert!(c.is_collapsed());
+    assert_eq!(c.collapse_count, 3);
+    assert!(!collapsed[0].children[0].is_collapsed());
+}
+
+#[test]
+fn exporters_collapse_below_depth() {
+    let _lock = exclusive();
+    use flame::chrome_trace::{write_chrome_trace_with_options, ChromeTraceOptions};
+    use flame::flamescope::{spans_to_speedscope_with, SpeedscopeOptions};
+    flame::clear();
+    flame::span_of("collapsed outer", || flame::span_of("collapsed inner", || {}));
+
+    let mut folded = vec![];
+    flame::dump_folded_with_options(&mut folded, &flame::FoldedOptions {
+        collapse_below_depth: Some(0),
+        ..Default::default()
+    }).unwrap();
+
+    let mut dot = vec![];
+    flame::dump_dot_with_options(&mut dot, &flame::spans(), &flame::DotOptions {
+        collapse_below_depth: Some(0),
+    }).unwrap();
+
+    let mut trace = vec![];
+    write_chrome_trace_with_options(&mut trace, &flame::threads()[.. 1], &ChromeTraceOptions {
+        collapse_below_depth: Some(0),
+    }).unwrap();
+
+    let speedscope = spans_to_speedscope_with(flame::spans(), SpeedscopeOptions {
+        collapse_below_depth: Some(0),
+        ..Default::default()
+    });
+    let speedscope = serde_json::to_vec(&speedscope).unwrap();
+
+    for out in [folded, dot, trace, speedscope] {
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("collapsed outer") && !out.contains("collapsed inner"), "{}", out);
+    }
+    flame::clear();
+}
+
+#[test]
+fn html_show_gaps() {
+    let _lock = exclusive();
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    flame::clear();
+    flame::start("parent");
+        sleep(Duration::from_millis(1));
+        flame::start("child");
+        flame::end("child");
+    flame::end("parent");
+
+    let mut html = vec![];
+    flame::dump_html_show_gaps(&mut html).unwrap();
+    let html = String::from_utf8(html).unwrap();
+    assert!(html.contains("\"[uninstrumented]\""));
+
+    let mut html = vec![];
+    flame::dump_html(&mut html).unwrap();
+    let html = String::from_utf8(html).unwrap();
+    assert!(!html.contains("\"[uninstrumented]\""));
+}
+
+#[test]
+fn dump_html_titled() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::span_of("work", || {});
+
+    let mut html = vec![];
+    flame::dump_html_titled(&mut html, &flame::spans(), &flame::HtmlOptions {
+        title: Some("threads=8".into()),
+        subtitle: Some("<release> & lto".into()),
+        collapse_below_depth: None,
+    }).unwrap();
+    let html = String::from_utf8(html).unwrap();
+    assert!(html.contains("<title>threads=8</title>"));
+    assert!(html.contains("<h1>threads=8</h1><h2>&lt;release&gt; &amp; lto</h2>"));
+
+    flame::span_of("outer", || flame::span_of("inner", || {}));
+    let mut html = vec![];
+    flame::dump_html_titled(&mut html, &flame::spans(), &flame::HtmlOptions {
+        collapse_below_depth: Some(0),
+        ..Default::default()
+    }).unwrap();
+    let html = String::from_utf8(html).unwrap();
+    assert!(html.contains(r#"name: "outer","#) && !html.contains(r#"name: "inner","#));
+
+    let mut html = vec![];
+    flame::dump_html(&mut html).unwrap();
+    let html = String::from_utf8(html).unwrap();
+    assert!(!html.contains("<title>") && !html.contains("<header>"));
+}
+
+#[test]
+fn explicit_parents() {
+    let _lock = shared();
+    flame::clear();
+    let first = flame::start_with_parent("first", None);
+    let second = flame::start_with_parent("second", None);
+    let second_child = flame::start_with_parent("second child", Some(second));
+    let first_child = flame::start_with_parent("first child", Some(first));
+    let grandchild = flame::start_with_parent("grandchild", Some(first_child));
+
+    flame::end_by_id(second_child);
+    flame::end_by_id(first);
+    flame::end_by_id(grandchild);
+    flame::end_by_id(second);
+    flame::end_by_id(first_child);
+
+    let spans = flame::spans();
+    assert_eq!(spans.len(), 2);
+    assert_eq!(spans[0].name, "first");
+    assert_eq!(spans[0].children.len(), 1);
+    assert_eq!(spans[0].children[0].name, "first child");
+    assert_eq!(spans[0].children[0].depth, 1);
+    assert_eq!(spans[0].children[0].children[0].name, "grandchild");
+    assert_eq!(spans[0].children[0].children[0].depth, 2);
+    assert_eq!(spans[1].name, "second");
+    assert_eq!(spans[1].children.len(), 1);
+    assert_eq!(spans[1].children[0].name, "second child");
+}
+
+#[test]
+fn explicit_parents_unrecorded() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::set_enabled(false);
+    let off = flame::start_with_parent("off", None);
+    flame::set_enabled(true);
+    assert_eq!(off, flame::UNRECORDED_ID);
+
+    let child = flame::start_with_parent("child of off", Some(off));
+    assert_eq!(child, flame::UNRECORDED_ID);
+    assert_eq!(flame::end_by_id(child), 0);
+    assert_eq!(flame::end_by_id(off), 0);
+
+    flame::suppressed(|| {
+        assert_eq!(flame::start_with_parent("suppressed", None), flame::UNRECORDED_ID);
+    });
+    assert!(flame::spans().is_empty());
+}
+
+#[test]
+#[should_panic(expected = "flame::end_by_id(0) called on a span that was started with `start`!")]
+fn end_by_id_stack_span() {
+    let _lock = shared();
+    flame::clear();
+    flame::start("stacked");
+    flame::end_by_id(0);
+}
+
+#[test]
+fn speedscope_thread_names() {
+    let _lock = exclusive();
+    use flame::flamescope::{threads_to_speedscope, Profile};
+    use std::thread::Builder;
+
+    flame::clear();
+    for name in &["alpha", "beta"] {
+        Builder::new().name(name.to_string()).spawn(|| {
+            flame::start("work");
+            flame::end("work");
+            flame::commit_thread();
+        }).unwrap().join().unwrap();
+    }
+
+    let file = threads_to_speedscope(&flame::threads());
+    let names: Vec<String> = file.profiles.iter().map(|profile| match *profile {
+        Profile::Evented { ref name, .. } => name.to_string(),
+        Profile::Sampled { ref name, .. } => name.to_string(),
+    }).collect();
+
+    assert_eq!(names.len(), 2);
+    assert!(names[0].starts_with("alpha"));
+    assert!(names[1].starts_with("beta"));
+    let file_name = file.name.unwrap();
+    assert!(file_name.contains("alpha") && file_name.contains("beta"));
+}
+
+#[test]
+fn aggregate_only() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::set_aggregate_only(true);
+
+    let mut total = 0;
+    for _ in 0 .. 10_000 {
+        flame::start("hot");
+        total += flame::end("hot");
+    }
+
+    flame::set_aggregate_only(false);
+
+    let spans = flame::spans();
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0].name, "hot");
+    assert_eq!(spans[0].collapse_count, 10_000);
+    assert_eq!(spans[0].delta, total);
+}
+
+#[test]
+fn end_suggests_close_name() {
+    let _lock = shared();
+    use flame::FlameError;
+    flame::clear();
+    flame::start("database query");
+    flame::start("parse");
+
+    let error = flame::checked::end("databse query").unwrap_err();
+    assert_eq!(error, FlameError::NameMismatch {
+        expected: "parse".into(),
+        found: "databse query".into(),
+        suggestion: Some("database query".into()),
+    });
+    assert!(error.to_string().contains("did you mean \"database query\"?"));
+}
+
+#[test]
+#[should_panic(expected = "did you mean parse?")]
+fn end_panic_suggests_close_name() {
+    let _lock = shared();
+    flame::clear();
+    flame::start("parse");
+    flame::end("prase");
+}
+
+#[test]
+fn ring_buffer() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::set_ring_buffer(3);
+
+    for i in 0 .. 5 {
+        flame::start(i.to_string());
+            flame::start("child");
+            flame::end("child");
+        flame::end(i.to_string());
+    }
+    flame::start("running");
+
+    flame::set_ring_buffer(0);
+
+    let spans = flame::spans();
+    let names: Vec<_> = spans.iter().map(|span| span.name.as_ref()).collect();
+    assert_eq!(names, vec!["2", "3", "4"]);
+    assert!(spans.iter().all(|span| span.children[0].name == "child"));
+
+    flame::end("running");
+    assert_eq!(flame::spans().len(), 4);
+}
+
+#[test]
+fn ring_buffer_guard_ended_explicitly() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::set_ring_buffer(3);
+
+    let guard = flame::start_guard("first");
+    flame::end("first");
+    flame::start("second");
+    drop(guard);
+    flame::end("second");
+
+    flame::set_ring_buffer(0);
+    let names: Vec<_> = flame::spans().into_iter().map(|span| span.name).collect();
+    assert_eq!(names, vec!["first", "second"]);
+}
+
+#[test]
+fn ring_buffer_keeps_open_explicit_spans() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::set_ring_buffer(3);
+
+    flame::start("a");
+    let job = flame::start_with_parent("job", None);
+    let step = flame::start_with_parent("step", Some(job));
+    flame::end("a");
+    flame::end_by_id(job);
+    flame::end_by_id(step);
+
+    flame::set_ring_buffer(0);
+    let spans = flame::spans();
+    let names: Vec<_> = spans.iter().map(|span| span.name.as_ref()).collect();
+    assert_eq!(names, vec!["a", "job"]);
+    assert_eq!(spans[1].children[0].name, "step");
+}
+
+#[test]
+fn rebase_to_zero() {
+    let _lock = exclusive();
+    let json = r#"[{
+        "id": 1,
+        "name": "worker",
+        "spans": [{
+            "name": "late start",
+            "start_ns": 1000,
+            "end_ns": 1500,
+            "delta": 500,
+            "depth": 0,
+            "children": [{
+                "name": "child",
+                "start_ns": 1100,
+                "end_ns": 1200,
+                "delta": 100,
+                "depth": 1,
+                "children": [],
+                "notes": [],
+                "collapse_count": 1
+            }],
+            "notes": [{ "name": "note", "description": null, "instant": 1300 }],
+            "collapse_count": 1
+        }]
+    }]"#;
+
+    let mut threads = flame::read_threads_json(json.as_bytes()).unwrap();
+    flame::rebase_to_zero(&mut threads);
+
+    let span = &threads[0].spans[0];
+    assert_eq!((span.start_ns, span.end_ns, span.delta), (0, 500, 500));
+    assert_eq!(span.notes[0].instant, 300);
+    let child = &span.children[0];
+    assert_eq!((child.start_ns, child.end_ns, child.delta), (100, 200, 100));
+}
+
+#[test]
+fn is_collapsed() {
+    let _lock = shared();
+    flame::clear();
+    flame::start("parent");
+        flame::start("leaf");
+        flame::end_collapse("leaf");
+        flame::start("leaf");
+        flame::end_collapse("leaf");
+        flame::start("other");
+        flame::end("other");
+    flame::end("parent");
+
+    let spans = flame::spans();
+    let children = &spans[0].children;
+    assert_eq!(children.len(), 2);
+    assert!(children[0].is_collapsed());
+    assert_eq!(children[0].collapse_count, 2);
+    assert!(!children[1].is_collapsed());
+    assert!(!spans[0].is_collapsed());
+    assert!(spans[0].into_json().contains("\"collapse_count\": 1"));
+}
+
+#[test]
+fn start_guard_collapse() {
+    let _lock = shared();
+    flame::clear();
+    flame::span_of("parent", || {
+        for _ in 0 .. 3 {
+            let _guard = flame::start_guard_collapse("leaf");
+        }
+    });
+
+    let children = &flame::spans()[0].children;
+    assert_eq!(children.len(), 1);
+    assert_eq!(children[0].collapse_count, 3);
+}
+
+#[test]
+fn fixed_session() {
+    let _lock = shared();
+    fn count(spans: &[flame::Span]) -> usize {
+        spans.iter().map(|span| 1 + count(&span.children)).sum()
+    }
+
+    let mut session = flame::FixedSession::<4>::new();
+    session.start("root");
+    for _ in 0 .. 5 {
+        session.start("child");
+        session.start("grandchild");
+        session.end("grandchild");
+        session.end("child");
+    }
+    session.end("root");
+
+    assert_eq!(session.len(), 4);
+    let spans = session.spans();
+    assert_eq!(count(&spans), 4);
+    assert_eq!(spans[0].name, "root");
+    assert_eq!(spans[0].children.len(), 2);
+    assert_eq!(spans[0].children[1].children.len(), 0);
+}
+
+#[test]
+fn collector() {
+    let _lock = shared();
+    let mut collector = flame::Collector::with_capacity(2);
+    collector.start("root", 1_000);
+    collector.note("begin", None::<&str>, 2_000);
+    collector.start("child", 3_000);
+    collector.start("dropped", 4_000);
+    collector.note("lost", None::<&str>, 4_000);
+    assert_eq!(collector.end("dropped", 5_000), 0);
+    assert_eq!(collector.end("child", 6_000), 3_000);
+    assert_eq!(collector.end("root", 10_000), 9_000);
+
+    let spans = collector.spans();
+    assert_eq!(collector.len(), 2);
+    assert_eq!((spans[0].start_ns, spans[0].end_ns), (1_000, 10_000));
+    assert_eq!(spans[0].notes[0].instant, 2_000);
+    assert_eq!(spans[0].children[0].name, "child");
+    assert!(spans[0].children[0].notes.is_empty());
+
+    collector.clear();
+    assert!(collector.spans().is_empty());
+}
+
+#[test]
+fn threads_include_empty() {
+    let _lock = exclusive();
+    use std::thread::Builder;
+    flame::clear();
+
+    Builder::new().name("idle".into()).spawn(|| {
+        flame::register_thread();
+    }).unwrap().join().unwrap();
+
+    assert!(!flame::threads().iter().any(|t| t.name.as_ref().map(|n| &n[..]) == Some("idle")));
+
+    let threads = flame::threads_include_empty();
+    let idle = threads.iter().find(|t| t.name.as_ref().map(|n| &n[..]) == Some("idle")).unwrap();
+    assert!(idle.spans.is_empty());
+}
+
+#[test]
+#[cfg(feature = "digest")]
+fn canonical_digest() {
+    let _lock = exclusive();
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    flame::clear();
+    flame::start("root");
+        flame::start("a");
+        flame::end("a");
+        flame::start("b");
+            flame::start("c");
+            flame::end("c");
+        flame::end("b");
+    flame::end("root");
+    let first = flame::spans();
+
+    flame::clear();
+    flame::start("root");
+        flame::start("b");
+            flame::start("c");
+            sleep(Duration::from_millis(1));
+            flame::end("c");
+        flame::end("b");
+        flame::start("a");
+        flame::end("a");
+    flame::end("root");
+    let second = flame::spans();
+
+    flame::clear();
+    flame::start("root");
+        flame::start("a");
+            flame::start("c");
+            flame::end("c");
+        flame::end("a");
+        flame::start("b");
+        flame::end("b");
+    flame::end("root");
+    let different = flame::spans();
+
+    assert_eq!(flame::canonical_digest(&first), flame::canonical_digest(&second));
+    assert_ne!(flame::canonical_digest(&first), flame::canonical_digest(&different));
+}
+
+#[test]
+fn suppressed() {
+    let _lock = shared();
+    flame::clear();
+    flame::start("outer");
+    let value = flame::suppressed(|| {
+        flame::start("noisy");
+        flame::note("ignored", None::<&str>);
+        flame::suppressed(|| {
+            flame::start("nested");
+            flame::end("nested");
+        });
+        flame::start("still noisy");
+        flame::end("still noisy");
+        flame::end("noisy");
+        5
+    });
+    flame::start("inner");
+    flame::end("inner");
+    flame::end("outer");
+
+    assert_eq!(value, 5);
+    let spans = flame::spans();
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0].children.len(), 1);
+    assert_eq!(spans[0].children[0].name, "inner");
+    assert!(spans[0].notes.is_empty());
+}
+
+#[test]
+fn max_events() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::set_max_events(Some(2));
+    flame::span_of("first", || {
+        flame::span_of("second", || {});
+        flame::span_of("dropped", || {
+            flame::note("lost", Some("with its span"));
+            flame::span_of("also dropped", || {});
+        });
+    });
+    flame::span_of("dropped too", || flame::note("lost", Some("with no span running")));
+    flame::set_max_events(None);
+
+    let spans = flame::spans();
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0].children.len(), 1);
+    assert!(spans[0].notes.is_empty());
+    assert_eq!(flame::dropped_span_count(), 3);
+
+    flame::clear();
+    assert_eq!(flame::dropped_span_count(), 0);
+}
+
+#[test]
+fn span_of_panic_closes_span() {
+    let _lock = shared();
+    use std::panic::catch_unwind;
+    flame::clear();
+
+    let result = catch_unwind(|| {
+        flame::span_of("outer", || {
+            flame::span_of("boom", || -> () { panic!("oh no") })
+        })
+    });
+    assert!(result.is_err());
+
+    let spans = flame::spans();
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0].name, "outer");
+    assert_eq!(spans[0].children[0].name, "boom");
+
+    // Nothing is left running
+    flame::start("after");
+    flame::end("after");
+    assert_eq!(flame::spans()[1].depth, 0);
+}
+
+#[test]
+fn note_seq() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::start("a");
+    flame::note("first", None::<&str>);
+    flame::note("second", None::<&str>);
+    flame::end("a");
+
+    let spans = flame::spans();
+    assert!(spans[0].notes[0].seq < spans[0].notes[1].seq);
+
+    // Two notes that a coarse clock gave the same instant
+    let json = r#"[{
+        "id": 1,
+        "name": null,
+        "spans": [{
+            "name": "a",
+            "start_ns": 0,
+            "end_ns": 10,
+            "delta": 10,
+            "depth": 0,
+            "children": [],
+            "notes": [
+                { "name": "second", "description": null, "instant": 5, "seq": 8 },
+                { "name": "first", "description": null, "instant": 5, "seq": 7 }
+            ],
+            "collapse_count": 1
+        }]
+    }]"#;
+    let threads = flame::read_threads_json(json.as_bytes()).unwrap();
+    let names: Vec<_> = threads[0].spans[0].notes_sorted().iter().map(|n| n.name.clone()).collect();
+    assert_eq!(names, vec!["first", "second"]);
+}
+
+#[test]
+fn speedscope_options() {
+    let _lock = exclusive();
+    use flame::flamescope::{spans_to_speedscope_with, SpeedscopeOptions};
+    flame::clear();
+    flame::start("a");
+    flame::end("a");
+
+    let file = spans_to_speedscope_with(flame::spans(), SpeedscopeOptions {
+        exporter: Some("flame 0.2".into()),
+        name: Some("nightly run".into()),
+        active_profile_index: Some(0),
+        collapse_below_depth: None,
+    });
+    let json = serde_json::to_string(&file).unwrap();
+    assert!(json.contains(r#""exporter":"flame 0.2""#));
+    assert!(json.contains(r#""name":"nightly run""#));
+    assert!(json.contains(r#""activeProfileIndex":0"#));
+}
+
+#[test]
+fn align_threads() {
+    let _lock = exclusive();
+    use std::thread::{sleep, spawn};
+    use std::time::Duration;
+    flame::clear();
+
+    for name in &["early", "late"] {
+        spawn(move || {
+            flame::start(*name);
+            flame::end(*name);
+            flame::commit_thread();
+        }).join().unwrap();
+        sleep(Duration::from_millis(5));
+    }
+
+    let mut threads: Vec<_> = flame::threads().into_iter().filter(|t| !t.spans.is_empty()).collect();
+    assert!(threads[1].epoch_offset_ns >= threads[0].epoch_offset_ns + 5_000_000);
+
+    flame::align_threads(&mut threads);
+    let early = &threads[0].spans[0];
+    let late = &threads[1].spans[0];
+    assert_eq!(early.name, "early");
+    assert!(late.start_ns >= early.end_ns + 5_000_000);
+    assert_eq!(threads[1].epoch_offset_ns, 0);
+}
+
+#[test]
+fn span_of_if_slow() {
+    let _lock = shared();
+    use std::thread::sleep;
+    use std::time::Duration;
+    flame::clear();
+
+    let threshold = Duration::from_millis(5);
+    flame::start("parent");
+    let fast = flame::span_of_if_slow("fast", threshold, || {
+        flame::span_of("fast child", || 1)
+    });
+    let slow = flame::span_of_if_slow("slow", threshold, || {
+        flame::span_of("slow child", || sleep(Duration::from_millis(10)));
+        2
+    });
+    flame::end("parent");
+
+    assert_eq!((fast, slow), (1, 2));
+    let spans = flame::spans();
+    let children = &spans[0].children;
+    assert_eq!(children.len(), 1);
+    assert_eq!(children[0].name, "slow");
+    assert_eq!(children[0].children[0].name, "slow child");
+}
+
+#[test]
+fn span_of_if_slow_keeps_other_spans() {
+    let _lock = shared();
+    use std::time::Duration;
+    flame::clear();
+
+    let detached = flame::span_of_if_slow("fast", Duration::from_secs(60), || {
+        flame::start_with_parent("detached", None)
+    });
+    flame::start("next");
+    flame::end("next");
+    flame::end_by_id(detached);
+
+    let names: Vec<_> = flame::spans().into_iter().map(|span| span.name).collect();
+    assert_eq!(names, vec!["detached", "next"]);
+}
+
+#[test]
+fn distinct_names() {
+    let _lock = exclusive();
+    use std::thread::spawn;
+    flame::clear();
+    flame::start("main");
+        flame::start("shared");
+        flame::end("shared");
+    flame::end("main");
+
+    spawn(|| {
+        flame::start("worker");
+            flame::start("shared");
+            flame::end("shared");
+        flame::end("worker");
+        flame::commit_thread();
+    }).join().unwrap();
+
+    let names: Vec<_> = flame::distinct_names().into_iter().collect();
+    assert_eq!(names, vec!["main", "shared", "worker"]);
+}
+
+#[test]
+fn text_dump_child_count() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::start("parent");
+    for name in &["a", "b", "c"] {
+        flame::start(*name);
+        flame::end(*name);
+    }
+    flame::end("parent");
+
+    let mut out = vec![];
+    flame::dump_text_to_writer_with_options(&mut out, &flame::TextDumpOptions {
+        show_child_count: true,
+        mark_leaves: true,
+    }).unwrap();
+    let out = String::from_utf8(out).unwrap();
+
+    let parent = out.lines().find(|line| line.contains("parent")).unwrap();
+    assert!(parent.ends_with("ms [3 children]"));
+    let leaf = out.lines().find(|line| line.contains("| a:")).unwrap();
+    assert!(leaf.ends_with("ms [leaf]"));
+}
+
+#[test]
+fn text_dump_notes() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::span_of("parent", || {
+        flame::note("started", None::<&str>);
+        flame::span_of("child", || flame::note("cache", Some("miss")));
+    });
+
+    let mut out = vec![];
+    flame::dump_text_to_writer(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+
+    assert!(lines[1].starts_with("| parent: "));
+    assert!(lines[2].starts_with("  * started @ +"), "{}", lines[2]);
+    assert!(lines[3].starts_with("  | child: "));
+    assert!(lines[4].starts_with("    * cache: miss @ +"), "{}", lines[4]);
+    assert!(lines[4].ends_with("ms"));
+    assert!(lines[5].starts_with("  + "));
+    flame::clear();
+}
+
+#[test]
+fn record_span() {
+    let _lock = shared();
+    flame::clear();
+    let request = flame::record_span("request", 100_000, 900_000, None);
+    flame::record_span("parse", 150_000, 300_000, Some(request));
+    let query = flame::record_span("query", 300_000, 800_000, Some(request));
+    flame::record_span("connect", 310_000, 400_000, Some(query));
+
+    let spans = flame::spans();
+    assert_eq!(spans.len(), 1);
+    let request = &spans[0];
+    assert_eq!((request.start_ns, request.end_ns, request.delta), (100_000, 900_000, 800_000));
+    assert_eq!(request.children.len(), 2);
+    assert_eq!(request.children[0].name, "parse");
+    assert_eq!(request.children[0].delta, 150_000);
+    let query = &request.children[1];
+    assert_eq!((query.name.as_ref(), query.depth, query.delta), ("query", 1, 500_000));
+    assert_eq!(query.children[0].name, "connect");
+    assert_eq!(query.children[0].depth, 2);
+}
+
+#[test]
+fn record_span_gated() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::set_enabled(false);
+    assert_eq!(flame::record_span("off", 0, 1_000, None), flame::UNRECORDED_ID);
+    flame::set_enabled(true);
+    flame::suppressed(|| flame::record_span("suppressed", 0, 1_000, None));
+    assert_eq!(flame::record_span("orphan", 0, 1_000, Some(flame::UNRECORDED_ID)), flame::UNRECORDED_ID);
+    assert!(flame::spans().is_empty());
+
+    flame::set_aggregate_only(true);
+    flame::record_span("batch", 0, 1_000, None);
+    flame::record_span("batch", 5_000, 8_000, None);
+    flame::set_aggregate_only(false);
+    let spans = flame::spans();
+    assert_eq!((spans[0].name.as_ref(), spans[0].collapse_count, spans[0].delta), ("batch", 2, 4_000));
+    flame::clear();
+}
+
+#[test]
+fn sequence_guard() {
+    let _lock = shared();
+    use flame::NoteLevel;
+    flame::clear();
+    {
+        let mut sequence = flame::start_sequence("handshake", &["hello", "auth", "ready", "done"]);
+        sequence.step("hello");
+        sequence.step("ready");
+        sequence.step("auth");
+    }
+
+    let spans = flame::spans();
+    assert_eq!(spans[0].name, "handshake");
+    let errors: Vec<_> = spans[0].notes.iter()
+        .filter(|note| note.level == NoteLevel::Error)
+        .map(|note| (note.name.as_ref(), note.description.as_ref().unwrap().as_ref()))
+        .collect();
+    assert_eq!(errors, vec![
+        ("unexpected step", r#"expected "auth", got "ready""#),
+        ("unexpected step", r#"expected "ready", got "auth""#),
+        ("unreached step", "done"),
+    ]);
+}
+
+#[test]
+fn speedscope_streamed_matches_in_memory() {
+    let _lock = exclusive();
+    use flame::flamescope::{spans_to_speedscope, write_spans};
+    flame::clear();
+    flame::span_of("outer", || {
+        flame::span_of("inner", || {});
+        flame::span_of("other", || {
+            flame::span_of("inner", || {});
+        });
+    });
+    flame::span_of("second", || {});
+
+    let expected = serde_json::to_vec(&spans_to_speedscope(flame::spans())).unwrap();
+    let mut streamed = vec![];
+    write_spans(&mut streamed, flame::spans()).unwrap();
+    assert_eq!(String::from_utf8(streamed).unwrap(), String::from_utf8(expected).unwrap());
+}
+
+#[test]
+fn reset_hooks_removes_commit_sink() {
+    let _lock = exclusive();
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    flame::clear();
+    let commits = Arc::new(AtomicUsize::new(0));
+    let counter = commits.clone();
+    // Other tests' threads commit through the sink too, so only count
+    // the ones spawned here
+    flame::set_commit_sink(move |thread| {
+        if thread.name.as_deref() == Some("reset_hooks_sink") {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+
+    let record = || ::std::thread::Builder::new().name("reset_hooks_sink".into()).spawn(|| {
+        flame::span_of("work", || {});
+        flame::commit_thread();
+    }).unwrap().join().unwrap();
+
+    record();
+    assert_eq!(commits.load(Ordering::SeqCst), 1);
+
+    flame::reset_hooks();
+    record();
+    assert_eq!(commits.load(Ordering::SeqCst), 1);
+    flame::clear();
+}
+
+#[test]
+fn nested_set() {
+    let _lock = shared();
+    flame::clear();
+    flame::span_of("root", || {
+        flame::span_of("a", || {
+            flame::span_of("a1", || {});
+        });
+        flame::span_of("b", || {});
+    });
+    flame::span_of("other", || {});
+
+    let rows = flame::to_nested_set(&flame::spans());
+    let bounds: Vec<_> = rows.iter().map(|row| (row.name.as_ref(), row.depth, row.lft, row.rgt)).collect();
+    assert_eq!(bounds, vec![
+        ("root", 0, 1, 8),
+        ("a", 1, 2, 5),
+        ("a1", 2, 3, 4),
+        ("b", 1, 6, 7),
+        ("other", 0, 9, 10),
+    ]);
+
+    // Every row is strictly inside of the closest shallower row before it.
+    for (i, row) in rows.iter().enumerate() {
+        assert!(row.lft < row.rgt);
+        if let Some(parent) = rows[..i].iter().rev().find(|parent| parent.depth + 1 == row.depth) {
+            assert!(parent.lft < row.lft && row.rgt < parent.rgt);
+        }
+    }
+}
+
+#[test]
+fn commit_on_panic() {
+    let _lock = exclusive();
+    fn crash(name: &'static str) {
+        let result = ::std::thread::Builder::new().name(name.into()).spawn(|| {
+            flame::start("outer");
+            flame::start("inner");
+            panic!("crashed while profiling");
+        }).unwrap().join();
+        assert!(result.is_err());
+    }
+
+    fn spans_of(name: &str) -> Vec<flame::Span> {
+        flame::threads().into_iter()
+            .filter(|thread| thread.name.as_deref() == Some(name))
+            .flat_map(|thread| thread.spans)
+            .collect()
+    }
+
+    flame::clear();
+    crash("lost");
+    assert!(spans_of("lost").is_empty());
+
+    flame::set_commit_on_panic(true);
+    crash("kept");
+    flame::set_commit_on_panic(false);
+
+    let spans = spans_of("kept");
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0].name, "outer");
+    assert_eq!(spans[0].children[0].name, "inner");
+    flame::clear();
+}
+
+#[test]
+fn name_prefixes() {
+    let _lock = shared();
+    flame::clear();
+    flame::with_prefix("app", || {
+        flame::span_of("parse", || {
+            flame::with_prefix("json", || {
+                flame::start("parse");
+                flame::end("parse");
+            });
+        });
+    });
+    flame::span_of("parse", || {});
+
+    let spans = flame::spans();
+    assert_eq!(spans[0].name, "app::parse");
+    assert_eq!(spans[0].children[0].name, "app::json::parse");
+    assert_eq!(spans[1].name, "parse");
+}
+
+#[test]
+fn spawn_named_records_call_site() {
+    let _lock = exclusive();
+    flame::clear();
+    let line = line!() + 1;
+    flame::spawn_named("worker", || {
+        flame::span_of("job", || {});
+    }).join().unwrap();
+
+    let threads = flame::threads();
+    let worker = threads.iter().find(|thread| thread.name.as_deref() == Some("worker")).unwrap();
+    assert_eq!(worker.spans[0].name, "job");
+    let spawned_at = worker.spawned_at.as_ref().unwrap();
+    assert!(spawned_at.starts_with(&format!("tests{}tests.rs:{}:", ::std::path::MAIN_SEPARATOR, line)), "{}", spawned_at);
+    flame::clear();
+}
+
+#[test]
+fn names_match_by_value() {
+    let _lock = shared();
+    use std::borrow::Cow;
+    let borrowed = || Cow::Borrowed("work");
+    let owned = || -> Cow<'static, str> { Cow::Owned("work".to_string()) };
+
+    for &(start_owned, end_owned) in &[(false, false), (false, true), (true, false), (true, true)] {
+        let start = if start_owned { owned() } else { borrowed() };
+        let end = if end_owned { owned() } else { borrowed() };
+
+        flame::clear();
+        flame::start(start.clone());
+        flame::note(end.clone(), None::<&str>);
+        flame::end(end.clone());
+
+        flame::with_prefix("lib", || {
+            flame::start(start.clone());
+            flame::end(end.clone());
+        });
+
+        flame::start(start.clone());
+        assert!(flame::checked::end(end.clone()).is_ok());
+
+        let spans = flame::spans();
+        assert_eq!(spans.len(), 3);
+        assert_eq!(spans[1].name, "lib::work");
+    }
+}
+
+#[test]
+fn raw_events_json() {
+    let _lock = exclusive();
+    flame::clear();
+    let root = flame::start_with_parent("root", None);
+    let first = flame::start_with_parent("first", Some(root));
+    flame::record_span("late child", 0, 1_000, Some(first));
+    flame::end_by_id(first);
+    flame::end_by_id(root);
+    flame::start("running");
+
+    let mut out = vec![];
+    flame::dump_raw_events_json(&mut out).unwrap();
+    flame::end("running");
+
+    let threads: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    let events = threads[0]["events"].as_array().unwrap();
+    let summary: Vec<_> = events.iter()
+        .map(|event| (event["id"].as_u64().unwrap(), event["parent"].as_u64(), event["name"].as_str().unwrap()))
+        .collect();
+    assert_eq!(summary, vec![
+        (0, None, "root"),
+        (1, Some(0), "first"),
+        (2, Some(1), "late child"),
+        (3, None, "running"),
+    ]);
+    assert_eq!(events[2]["delta"], 1_000);
+    assert!(events[3]["end_ns"].is_null());
+}
+
+#[test]
+fn ffi_scope_handles() {
+    let _lock = shared();
+    use std::ffi::CString;
+    flame::clear();
+    let outer_name = CString::new("outer").unwrap();
+    let inner_name = CString::new("inner").unwrap();
+
+    let outer = flame::flame_scope_begin(outer_name.as_ptr());
+    let inner = flame::flame_scope_begin(inner_name.as_ptr());
+    assert!(!outer.is_null() && !inner.is_null());
+    flame::flame_scope_end(inner);
+    let second = flame::flame_scope_begin(inner_name.as_ptr());
+    flame::flame_scope_end(second);
+    flame::flame_scope_end(outer);
+    flame::flame_scope_end(::std::ptr::null_mut());
+
+    let spans = flame::spans();
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0].name, "outer");
+    let children: Vec<_> = spans[0].children.iter().map(|child| child.name.as_ref()).collect();
+    assert_eq!(children, vec!["inner", "inner"]);
+}
+
+#[test]
+fn ffi_readout() {
+    let _lock = exclusive();
+    flame::clear();
+    assert_eq!((flame::flame_span_count(), flame::flame_total_ns()), (0, 0));
+
+    let root = flame::record_span("root", 0, 30_000, None);
+    flame::record_span("child", 0, 10_000, Some(root));
+    flame::record_span("other", 40_000, 50_000, None);
+    assert_eq!(flame::flame_span_count(), 3);
+    assert_eq!(flame::flame_total_ns(), 40_000);
+    flame::clear();
+}
+
+#[test]
+fn profile_context() {
+    let _lock = shared();
+    flame::clear();
+    assert_eq!(flame::current_context().to_string(), "no spans running");
+
+    let report = flame::span_of("outer", || {
+        flame::span_of("middle", || {
+            flame::span_of("inner", || {
+                ::std::thread::sleep(::std::time::Duration::from_millis(1));
+                format!("failed to load\n{}", flame::current_context())
+            })
+        })
+    });
+
+    let lines: Vec<_> = report.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[1].starts_with("in inner (running for "));
+    assert!(lines[2].starts_with("in middle (running for "));
+    assert!(lines[3].starts_with("in outer (running for "));
+    assert!(lines[1..].iter().all(|line| line.ends_with("ms)")));
+
+    let context = flame::span_of("outer", flame::current_context);
+    assert_eq!(context.spans.len(), 1);
+    assert_eq!(context.spans[0].0, "outer");
+}
+
+#[test]
+fn note_tracks() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::span_of("download", || {
+        flame::note_on_track("video", "chunk", Some("0"));
+        flame::note_on_track("audio", "chunk", Some("0"));
+        flame::note("paused", None::<&str>);
+        flame::note_on_track("video", "chunk", Some(format!("{}", 1)));
+    });
+
+    let spans = flame::spans();
+    let tracks: Vec<_> = spans[0].notes_by_track().into_iter()
+        .map(|(track, notes)| (track, notes.len()))
+        .collect();
+    assert_eq!(tracks, vec![(Some("video".into()), 2), (Some("audio".into()), 1), (None, 1)]);
+
+    let mut out = vec![];
+    flame::dump_json(&mut out).unwrap();
+    let threads = flame::read_threads_json(&out[..]).unwrap();
+    let notes = &threads[0].spans[0].notes;
+    assert_eq!(notes[1].track, Some("audio".into()));
+    assert_eq!(notes[2].track, None);
+}
+
+#[test]
+fn zero_delta_warning() {
+    let _lock = exclusive();
+    flame::clear();
+    assert!(flame::clock_resolution().unwrap() > ::std::time::Duration::from_nanos(0));
+
+    // What a coarse clock reports for spans that are shorter than a tick
+    let root = flame::record_span("frame", 1_000_000, 2_000_000, None);
+    for _ in 0 .. 3 {
+        flame::record_span("tiny", 1_000_000, 1_000_000, Some(root));
+    }
+    let warning = flame::zero_delta_warning(&flame::spans()).unwrap();
+    assert_eq!((warning.zero_delta, warning.total), (3, 4));
+
+    let mut out = vec![];
+    flame::dump_text_to_writer(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.lines().last().unwrap().starts_with("warning: 3 of 4 spans took 0ns"));
+
+    flame::clear();
+    flame::record_span("slow", 0, 1_000, None);
+    assert_eq!(flame::zero_delta_warning(&flame::spans()), None);
+}
+
+#[test]
+fn unified_tree() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::span_of("main work", || {
+        flame::span_of("step", || {});
+    });
+    ::std::thread::Builder::new().name("helper".into()).spawn(|| {
+        flame::span_of("helper work", || {});
+    }).unwrap().join().unwrap();
+
+    let root = flame::unified_tree("process");
+    assert_eq!(root.name, "process");
+    assert_eq!(root.depth, 0);
+    assert_eq!(root.children.len(), 2);
+
+    let main = &root.children[0];
+    assert_eq!(main.depth, 1);
+    assert_eq!(main.children[0].name, "main work");
+    assert_eq!(main.children[0].depth, 2);
+    assert_eq!(main.children[0].children[0].depth, 3);
+
+    let helper = &root.children[1];
+    assert!(helper.name.starts_with("helper"));
+    assert_eq!(helper.children[0].name, "helper work");
+    assert_eq!(helper.children[0].depth, 2);
+
+    for thread in &root.children {
+        assert!(root.start_ns <= thread.start_ns && thread.end_ns <= root.end_ns);
+    }
+    flame::clear();
+}
+
+#[test]
+fn pause_for_wait_reason() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::span_of("request", || {
+        flame::span_of("parse", || {});
+        flame::pause_for("db");
+        flame::resume();
+    });
+
+    let spans = flame::spans();
+    let children = &spans[0].children;
+    assert_eq!(children[0].wait_reason, None);
+    assert_eq!(children[1].name, "[waiting on db]");
+    assert_eq!(children[1].wait_reason, Some("db".into()));
+
+    let mut out = vec![];
+    flame::dump_html(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains(r#"wait: "db","#) && out.contains("d.wait"));
+}
+
+#[test]
+#[should_panic(expected = "resume() called while not paused")]
+fn resume_without_pause() {
+    let _lock = shared();
+    flame::clear();
+    let _guard = flame::start_guard("work");
+    flame::resume();
+}
+
+#[test]
+fn graft_spans() {
+    let _lock = exclusive();
+    flame::clear();
+    let computed = ::std::thread::spawn(|| {
+        flame::span_of("compute", || {
+            flame::note("halfway", None::<&str>);
+            flame::span_of("inner", || {});
+        });
+        flame::spans()
+    }).join().unwrap();
+
+    flame::span_of("request", || {
+        flame::span_of("wait", || {
+            flame::graft_spans(computed);
+        });
+        flame::span_of("respond", || {});
+    });
+
+    let spans = flame::spans();
+    assert_eq!(spans.len(), 1);
+    let wait = &spans[0].children[0];
+    assert_eq!(wait.name, "wait");
+    let compute = &wait.children[0];
+    assert_eq!((compute.name.as_ref(), compute.depth), ("compute", 2));
+    assert_eq!(compute.notes[0].name, "halfway");
+    assert_eq!((compute.children[0].name.as_ref(), compute.children[0].depth), ("inner", 3));
+    assert_eq!(spans[0].children[1].name, "respond");
+    flame::clear();
+}
+
+#[test]
+fn rollup_recursion() {
+    let _lock = shared();
+    flame::clear();
+    // walk -> visit -> walk -> visit -> leaf, with some time of its own at each level
+    let root = flame::record_span("walk", 0, 100_000, None);
+    let visit = flame::record_span("visit", 10_000, 90_000, Some(root));
+    let inner = flame::record_span("walk", 20_000, 80_000, Some(visit));
+    let inner_visit = flame::record_span("visit", 30_000, 70_000, Some(inner));
+    flame::record_span("leaf", 40_000, 50_000, Some(inner_visit));
+    flame::record_span("leaf", 95_000, 99_000, Some(root));
+
+    let spans = flame::rollup_recursion(flame::spans());
+    assert_eq!(spans.len(), 1);
+    let walk = &spans[0];
+    assert_eq!((walk.name.as_ref(), walk.delta, walk.collapse_count), ("walk", 100_000, 2));
+
+    let names: Vec<_> = walk.children.iter().map(|child| child.name.as_ref()).collect();
+    assert_eq!(names, vec!["visit", "leaf"]);
+
+    // Both `visit`s are merged, and the inner `leaf` moved up out of them.
+    let visit = &walk.children[0];
+    assert_eq!((visit.delta, visit.collapse_count, visit.depth), (20_000 + 30_000 + 10_000, 2, 1));
+    assert_eq!(visit.children.len(), 1);
+    assert_eq!(visit.children[0].name, "leaf");
+    assert_eq!((visit.children[0].delta, visit.children[0].depth), (10_000, 2));
+
+    // The top-level `leaf` is left alone.
+    assert_eq!(walk.children[1].delta, 4_000);
+}
+
+#[test]
+fn enter_tokens() {
+    let _lock = shared();
+    flame::clear();
+    let line = line!() + 2;
+    {
+        let _token = flame::enter();
+        let mut named = flame::enter();
+        flame::span_of("work", || {});
+        named.name("named");
+    }
+
+    let spans = flame::spans();
+    assert_eq!(spans.len(), 1);
+    let location = format!("tests{}tests.rs:{}:", ::std::path::MAIN_SEPARATOR, line);
+    assert!(spans[0].name.starts_with(&location), "{}", spans[0].name);
+    let named = &spans[0].children[0];
+    assert_eq!(named.name, "named");
+    assert_eq!(named.children[0].name, "work");
+}
+
+#[test]
+fn folded_thread_prefix() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::record_span("main", 0, 10_000, None);
+    ::std::thread::Builder::new().name("worker".into()).spawn(|| {
+        let job = flame::record_span("job", 0, 5_000, None);
+        flame::record_span("step", 1_000, 3_000, Some(job));
+    }).unwrap().join().unwrap();
+
+    let mut out = vec![];
+    flame::dump_folded_with_options(&mut out, &flame::FoldedOptions {
+        separator: "|".into(),
+        thread_prefix: true,
+        collapse_below_depth: None,
+    }).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<_> = out.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("folded_thread_prefix (") && lines[0].ends_with(")|main 10000"), "{}", lines[0]);
+    assert!(lines[1].starts_with("worker (") && lines[1].ends_with(")|job 3000"), "{}", lines[1]);
+    assert!(lines[2].starts_with("worker (") && lines[2].ends_with(")|job|step 2000"), "{}", lines[2]);
+
+    let mut out = vec![];
+    flame::dump_folded(&mut out).unwrap();
+    assert!(String::from_utf8(out).unwrap().contains("\njob;step 2000\n"));
+    flame::clear();
+}
+
+#[test]
+fn rebase_depth_saturates() {
+    let _lock = shared();
+    flame::clear();
+    let root = flame::record_span("root", 0, 2_000, None);
+    flame::record_span("child", 0, 1_000, Some(root));
+
+    let mut span = flame::spans().remove(0);
+    flame::rebase_depth(&mut span, u16::MAX);
+    assert_eq!((span.depth, span.children[0].depth), (u16::MAX, u16::MAX));
+}
+
+#[test]
+fn extract_subtree() {
+    let _lock = shared();
+    flame::clear();
+    flame::span_of("frame", || {
+        flame::span_of("update", || {
+            flame::span_of("physics", || {
+                flame::span_of("collide", || {});
+            });
+        });
+    });
+
+    let physics = {
+        let spans = flame::spans();
+        flame::extract(&spans, "physics").unwrap()
+    };
+    assert_eq!(physics.name, "physics");
+    assert_eq!(physics.depth, 0);
+    assert_eq!(physics.children[0].name, "collide");
+    assert_eq!(physics.children[0].depth, 1);
+
+    assert!(flame::extract(&flame::spans(), "missing").is_none());
+}
+
+#[test]
+fn note_as_span() {
+    let _lock = exclusive();
+    flame::clear();
+    let value = flame::span_of("load", || {
+        flame::note_as_span("decompress", || 42)
+    });
+    assert_eq!(value, 42);
+
+    let spans = flame::spans();
+    assert!(!spans[0].annotation);
+    let decompress = &spans[0].children[0];
+    assert_eq!(decompress.name, "decompress");
+    assert!(decompress.annotation);
+
+    let mut out = vec![];
+    flame::dump_html(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("annotation: true,") && out.contains("d.annotation"));
+}
+
+#[test]
+fn try_end() {
+    let _lock = shared();
+    use std::error::Error;
+    flame::clear();
+    match flame::try_end("nothing") {
+        Err(flame::FlameError::NoActiveSpan { ref name }) => assert_eq!(name, "nothing"),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    flame::start("outer");
+    let error = flame::try_end("other").unwrap_err();
+    let error: &dyn Error = &error;
+    assert_eq!(error.to_string(), r#"attempted to end "other" while "outer" was running; did you mean "outer"?"#);
+
+    assert!(flame::try_end("outer").is_ok());
+    assert_eq!(flame::spans()[0].name, "outer");
+}
+
+#[test]
+fn spans_for_thread() {
+    let _lock = exclusive();
+    flame::clear();
+    let id = ::std::thread::spawn(|| {
+        flame::span_of("worker", || {});
+        let id = flame::threads()[0].id;
+        flame::commit_thread();
+        id
+    }).join().unwrap();
+
+    let spans = flame::spans_for_thread(id).unwrap();
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0].name, "worker");
+
+    flame::span_of("here", || {});
+    let my_id = flame::threads()[0].id;
+    assert_eq!(flame::spans_for_thread(my_id).unwrap()[0].name, "here");
+
+    assert!(flame::spans_for_thread(usize::MAX).is_none());
+}
+
+#[test]
+fn shrink_to_fit() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::set_ring_buffer(1);
+    flame::start("burst");
+    for _ in 0 .. 10_000 {
+        flame::span_of("work", || {});
+    }
+    flame::end("burst");
+    flame::set_ring_buffer(0);
+
+    // The burst went into the ring buffer, but its events' memory is kept
+    let before = flame::event_capacity();
+    assert!(before >= 10_000);
+
+    flame::shrink_to_fit();
+    assert!(flame::event_capacity() < before);
+    assert_eq!(flame::spans()[0].name, "burst");
+}
+
+#[test]
+fn approx_memory_bytes() {
+    let _lock = exclusive();
+    flame::clear();
+    let empty = flame::approx_memory_bytes();
+    for i in 0 .. 1_000 {
+        flame::span_of(format!("span number {:>10}", i), || {
+            flame::note("note", Some("x".repeat(100)));
+        });
+    }
+
+    // At least the owned names and descriptions
+    let full = flame::approx_memory_bytes();
+    assert!(full >= empty + 1_000 * (20 + 100), "{} {}", empty, full);
+    flame::clear();
+    assert!(flame::approx_memory_bytes() < full);
+}
+
+#[test]
+fn start_ts() {
+    let _lock = shared();
+    flame::clear();
+    let start_ns = flame::start_ts("timed");
+    flame::end("timed");
+    assert_eq!(flame::spans()[0].start_ns, start_ns);
+}
+
+#[test]
+fn deeply_nested_spans() {
+    let _lock = shared();
+    flame::clear();
+    for _ in 0 .. 100_000 {
+        flame::start("nested");
+    }
+    for _ in 0 .. 100_000 {
+        flame::end("nested");
+    }
+
+    let mut span = flame::spans().pop().unwrap();
+    let mut levels = 1;
+    // Take the tree apart one level at a time, since dropping it
+    // all at once would recurse just as deeply
+    while let Some(child) = span.children.pop() {
+        span = child;
+        levels += 1;
+    }
+    assert_eq!(levels, 100_000);
+    assert_eq!(span.depth, u16::MAX);
+    flame::clear();
+}
+
+#[test]
+fn set_enabled() {
+    let _lock = exclusive();
+    use std::time::Instant;
+
+    fn run() -> u128 {
+        let started = Instant::now();
+        for _ in 0 .. 100_000 {
+            flame::start("hot");
+            flame::note("inside", None::<&str>);
+            flame::end("hot");
+        }
+        started.elapsed().as_nanos()
+    }
+
+    flame::clear();
+    let on = run();
+
+    flame::clear();
+    flame::set_enabled(false);
+    assert!(!flame::is_enabled());
+    let off = run();
+    assert!(flame::spans().is_empty());
+    assert_eq!(flame::checked::end("hot"), Err(flame::FlameError::Disabled));
+    flame::set_enabled(true);
+
+    assert!(off < on, "{}ns with recording off, {}ns with it on", off, on);
+    flame::clear();
+}
+
+#[test]
+fn note_after_thread_local_destroyed() {
+    let _lock = exclusive();
+    use std::cell::RefCell;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    static NOTED: AtomicBool = AtomicBool::new(false);
+
+    struct NoteOnDrop;
+    impl Drop for NoteOnDrop {
+        fn drop(&mut self) {
+            flame::note("thread exiting", None::<&str>);
+            NOTED.store(true, Ordering::SeqCst);
+        }
+    }
+
+    thread_local!(static NOTE_ON_DROP: RefCell<Option<NoteOnDrop>> = const { RefCell::new(None) });
+
+    ::std::thread::spawn(|| {
+        // Thread-locals are destroyed in the reverse of the order that
+        // they were first used in, so this one outlives flame's
+        NOTE_ON_DROP.with(|slot| *slot.borrow_mut() = Some(NoteOnDrop));
+        flame::span_of("work", || {});
+    }).join().unwrap();
+
+    assert!(NOTED.load(Ordering::SeqCst));
+}
+
+#[test]
+fn annotate_times() {
+    let _lock = shared();
+    flame::clear();
+    let parent = flame::record_span("parent", 0, 100_000, None);
+    flame::record_span("first", 10_000, 30_000, Some(parent));
+    flame::record_span("second", 50_000, 90_000, Some(parent));
+
+    let mut spans = flame::spans();
+    assert_eq!(spans[0].inclusive_ns, None);
+    flame::annotate_times(&mut spans);
+
+    let parent = &spans[0];
+    assert_eq!(parent.inclusive_ns, Some(100_000));
+    assert_eq!(parent.exclusive_ns, Some(40_000));
+    assert_eq!(parent.children[0].exclusive_ns, Some(20_000));
+    assert_eq!(parent.children[1].inclusive_ns, Some(40_000));
+
+    let json = serde_json::to_value(parent).unwrap();
+    assert_eq!(json["exclusive_ns"], 40_000);
+}
+
+#[test]
+fn folded_escapes_separators() {
+    let _lock = exclusive();
+    flame::clear();
+    let outer = flame::record_span("a;b", 0, 10_000, None);
+    flame::record_span("line\nbreak", 0, 4_000, Some(outer));
+
+    let mut out = vec![];
+    flame::dump_folded_to_writer(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.ends_with("a_b 6000\na_b;line_break 4000\n"), "{}", out);
+    flame::clear();
+}
+
+#[test]
+fn speedscope_opens_busiest_thread() {
+    let _lock = exclusive();
+    use flame::flamescope::threads_to_speedscope;
+
+    flame::clear();
+    flame::record_span("short", 0, 1_000, None);
+    ::std::thread::spawn(|| {
+        flame::record_span("long", 0, 5_000, None);
+        flame::commit_thread();
+    }).join().unwrap();
+
+    let file = threads_to_speedscope(&flame::threads());
+    assert_eq!(file.profiles.len(), 2);
+    assert_eq!(file.active_profile_index, Some(1));
+    flame::clear();
+}
+
+#[test]
+fn chrome_trace() {
+    let _lock = exclusive();
+    flame::clear();
+    let parent = flame::record_span("outer", 1_000, 9_000, None);
+    flame::record_span("inner", 2_000, 5_000, Some(parent));
+    flame::span_of("noted", || flame::note("hello", Some("world")));
+
+    let mut out = vec![];
+    flame::chrome_trace::write_chrome_trace(&mut out, &flame::threads()[.. 1]).unwrap();
+    let events: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    let events = events.as_array().unwrap();
+
+    let phases: Vec<_> = events.iter().map(|event| (event["name"].as_str().unwrap(), event["ph"].as_str().unwrap())).collect();
+    assert_eq!(phases, vec![
+        ("outer", "B"), ("inner", "B"), ("inner", "E"), ("outer", "E"),
+        ("noted", "B"), ("hello", "i"), ("noted", "E"),
+    ]);
+    assert_eq!(events[5]["s"], "t");
+    assert_eq!(events[5]["args"]["description"], "world");
+    assert_eq!(events[1]["pid"], 0);
+    assert_eq!(events[1]["tid"], flame::threads()[0].id);
+    let offset = events[0]["ts"].as_f64().unwrap() - 1.0;
+    assert!((events[2]["ts"].as_f64().unwrap() - offset - 5.0).abs() < 1e-6);
+    flame::clear();
+}
+
+#[test]
+fn dispatch_span() {
+    let _lock = shared();
+    flame::clear();
+    let program = [("push", 300), ("add", 200), ("pop", 100)];
+    flame::span_of("interpret", || {
+        for &(opcode, times) in &program {
+            for _ in 0 .. times {
+                flame::dispatch_span(opcode, || {});
+            }
+        }
+    });
+
+    let spans = flame::spans();
+    let counts: Vec<_> = spans[0].children.iter().map(|span| (&*span.name, span.collapse_count)).collect();
+    assert_eq!(counts, vec![("push", 300), ("add", 200), ("pop", 100)]);
+}
+
+#[test]
+fn aggregate() {
+    let _lock = shared();
+    flame::clear();
+    let request = flame::record_span("request", 0, 100_000, None);
+    flame::record_span("query", 0, 10_000, Some(request));
+    flame::record_span("query", 10_000, 30_000, Some(request));
+    flame::record_span("query", 30_000, 60_000, Some(request));
+
+    let stats = flame::aggregate(&flame::spans());
+    assert_eq!(stats.len(), 2);
+    let query = &stats[1];
+    assert_eq!((&*query.name, query.depth), ("query", 1));
+    assert_eq!(query.count, 3);
+    assert_eq!(query.total_ns, 60_000);
+    assert_eq!(query.mean_ns, 20_000);
+    assert_eq!((query.min_ns, query.max_ns), (10_000, 30_000));
+    flame::clear();
+}
+
+#[test]
+fn json_time_scale() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::record_span("five", 0, 5_000_000, None);
+
+    let mut out = vec![];
+    flame::dump_json_with_options(&mut out, &flame::JsonOptions {
+        time_scale: 1e-6,
+        ..Default::default()
+    }).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(json["threads"][0]["spans"][0]["delta"], 5.0);
+    assert_eq!(json["threads"][0]["spans"][0]["end_ns"], 5.0);
+
+    // The recorded data isn't changed
+    assert_eq!(flame::spans()[0].delta, 5_000_000);
+    flame::clear();
+}
+
+#[test]
+fn percentiles() {
+    let _lock = exclusive();
+    flame::clear();
+    let request = flame::record_span("request", 0, 1_000_000, None);
+    for i in 0 .. 5 {
+        flame::record_span("query", i * 100_000, i * 100_000 + (i + 1) * 10_000, Some(request));
+    }
+
+    let spans = flame::spans();
+    assert_eq!(
+        flame::percentiles(&spans, "query", &[0.0, 50.0, 90.0, 100.0]),
+        vec![(0.0, 10_000), (50.0, 30_000), (90.0, 46_000), (100.0, 50_000)]
+    );
+    assert_eq!(flame::percentiles(&spans, "request", &[1.0, 99.0]), vec![(1.0, 1_000_000), (99.0, 1_000_000)]);
+    assert!(flame::percentiles(&spans, "missing", &[50.0]).is_empty());
+    flame::clear();
+}
+
+#[test]
+fn guard_after_explicit_end() {
+    let _lock = shared();
+    flame::clear();
+    flame::start("parent");
+    let guard = flame::start_guard("child");
+    flame::end("child");
+    drop(guard);
+    flame::end("parent");
+
+    let spans = flame::spans();
+    assert_eq!(spans.len(), 1);
+    let child = &spans[0].children[0];
+    assert_eq!(child.name, "child");
+    assert_eq!(child.notes[0].name, "ended twice");
+    assert_eq!(child.notes[0].level, flame::NoteLevel::Warn);
+}
+
+#[test]
+fn set_clock() {
+    let _lock = exclusive();
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static NOW_NS: AtomicU64 = AtomicU64::new(0);
+
+    struct MockClock;
+    impl flame::Clock for MockClock {
+        fn now_ns(&self) -> u64 {
+            NOW_NS.load(Ordering::SeqCst)
+        }
+    }
+
+    flame::clear();
+    flame::set_clock(Box::new(MockClock));
+    NOW_NS.store(1_000_000, Ordering::SeqCst);
+    flame::start("mocked");
+    NOW_NS.store(3_500_000, Ordering::SeqCst);
+    assert_eq!(flame::end("mocked"), 2_500_000);
+    flame::reset_hooks();
+
+    let span = &flame::spans()[0];
+    assert_eq!((span.start_ns, span.end_ns, span.delta), (1_000_000, 3_500_000, 2_500_000));
+
+    // A clock that goes backwards doesn't break aggregated spans
+    flame::clear();
+    flame::set_clock(Box::new(MockClock));
+    flame::set_aggregate_only(true);
+    flame::start("backwards");
+    NOW_NS.store(1_000_000, Ordering::SeqCst);
+    assert_eq!(flame::current_context().spans, vec![("backwards".into(), 0)]);
+    assert_eq!(flame::end("backwards"), 0);
+    flame::set_aggregate_only(false);
+    flame::reset_hooks();
+    assert_eq!(flame::spans()[0].delta, 0);
+    flame::clear();
+}
+
+#[test]
+fn clock_resolution_of_installed_clock() {
+    let _lock = exclusive();
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Duration;
+
+    static READS: AtomicU64 = AtomicU64::new(0);
+
+    // Ticks once a millisecond, every thousand reads
+    struct CoarseClock;
+    impl flame::Clock for CoarseClock {
+        fn now_ns(&self) -> u64 {
+            READS.fetch_add(1, Ordering::SeqCst) / 1000 * 1_000_000
+        }
+    }
+
+    struct StoppedClock;
+    impl flame::Clock for StoppedClock {
+        fn now_ns(&self) -> u64 {
+            5_000
+        }
+    }
+
+    flame::set_clock(Box::new(CoarseClock));
+    assert_eq!(flame::clock_resolution(), Some(Duration::from_millis(1)));
+    flame::set_clock(Box::new(StoppedClock));
+    assert_eq!(flame::clock_resolution(), None);
+    flame::reset_hooks();
+}
+
+#[test]
+fn max_note_len() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::set_max_note_len(10);
+    flame::span_of("query", || {
+        flame::note("sql", Some("SELECT * FROM users WHERE id = 1"));
+    });
+    flame::set_max_note_len(usize::MAX);
+
+    let note = &flame::spans()[0].notes[0];
+    assert_eq!(note.name, "sql");
+    assert_eq!(note.description.as_ref().unwrap(), "SELECT * F…");
+}
+
+#[test]
+fn start_async() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::start("request");
+    let guard = flame::start_async("fetch");
+    flame::end("request");
+    // Nothing shows up until the async span ends
+    assert!(flame::spans()[0].children.is_empty());
+
+    // The guard is dropped on another thread, like a future that was
+    // resumed by a different worker
+    ::std::thread::spawn(move || {
+        ::std::thread::sleep(::std::time::Duration::from_millis(2));
+        drop(guard);
+    }).join().unwrap();
+
+    let spans = flame::spans();
+    let fetch = &spans[0].children[0];
+    assert_eq!(fetch.name, "fetch");
+    assert!(fetch.delta >= 2_000_000);
+    assert_eq!(fetch.end_ns - fetch.start_ns, fetch.delta);
+}
+
+#[test]
+fn spans_with_note_markers() {
+    let _lock = shared();
+    flame::clear();
+    flame::span_of("outer", || {
+        flame::span_of("first", || {});
+        ::std::thread::sleep(::std::time::Duration::from_millis(1));
+        flame::note("checkpoint", None::<&str>);
+        ::std::thread::sleep(::std::time::Duration::from_millis(1));
+        flame::span_of("second", || {});
+    });
+
+    let spans = flame::spans_with_note_markers();
+    let outer = &spans[0];
+    let names: Vec<_> = outer.children.iter().map(|child| &*child.name).collect();
+    assert_eq!(names, vec!["first", "checkpoint", "second"]);
+
+    let marker = &outer.children[1];
+    assert_eq!(marker.start_ns, outer.notes[0].instant);
+    assert_eq!(marker.end_ns, marker.start_ns);
+    assert_eq!((marker.delta, marker.depth), (0, 1));
+}
+
+#[test]
+fn span_of_catch() {
+    let _lock = shared();
+    flame::clear();
+    assert_eq!(flame::span_of_catch("fine", || 5).unwrap(), 5);
+    let result = flame::span_of_catch("boom", || -> () { panic!("oh no") });
+    assert_eq!(*result.unwrap_err().downcast::<&str>().unwrap(), "oh no");
+
+    flame::span_of("after", || {});
+    let spans = flame::spans();
+    let names: Vec<_> = spans.iter().map(|span| (&*span.name, span.depth)).collect();
+    assert_eq!(names, vec![("fine", 0), ("boom", 0), ("after", 0)]);
+}
+
+#[test]
+fn budget_report() {
+    let _lock = exclusive();
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    flame::clear();
+    let request = flame::record_span("request", 0, 10_000_000, None);
+    flame::record_span("query", 0, 3_000_000, Some(request));
+    flame::record_span("render", 3_000_000, 4_000_000, Some(request));
+
+    let mut budgets = HashMap::new();
+    budgets.insert("query".into(), Duration::from_millis(2));
+    budgets.insert("render".into(), Duration::from_millis(2));
+
+    let report = flame::budget_report(&budgets);
+    assert_eq!(report.len(), 1);
+    assert_eq!(report[0].name, "query");
+    assert_eq!(report[0].path, vec!["request", "query"]);
+    assert_eq!((report[0].actual_ns, report[0].budget_ns, report[0].overage_ns), (3_000_000, 2_000_000, 1_000_000));
+    flame::clear();
+}
+
+#[test]
+fn attributes() {
+    let _lock = exclusive();
+    use flame::AttrValue;
+
+    flame::clear();
+    flame::span_of("query", || {
+        flame::attr("table", AttrValue::Str("users".into()));
+        flame::attr("rows", AttrValue::U64(1234));
+        flame::attr("cached", AttrValue::Bool(false));
+    });
+
+    let spans = flame::spans();
+    assert_eq!(spans[0].attributes[1], ("rows".into(), AttrValue::U64(1234)));
+
+    let json = serde_json::to_value(&spans[0]).unwrap();
+    assert_eq!(json["attributes"][0], serde_json::json!(["table", "users"]));
+    let span: flame::Span = serde_json::from_value(json).unwrap();
+    assert_eq!(span.attributes, spans[0].attributes);
+
+    let mut out = vec![];
+    flame::chrome_trace::write_chrome_trace(&mut out, &flame::threads()[.. 1]).unwrap();
+    let events: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(events[0]["args"], serde_json::json!({ "table": "users", "rows": 1234, "cached": false }));
+}
+
+#[test]
+fn span_lock() {
+    let _lock = exclusive();
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    flame::clear();
+    let mutex = Arc::new(Mutex::new(0));
+    let holder = {
+        let mutex = mutex.clone();
+        let (locked_tx, locked_rx) = ::std::sync::mpsc::channel();
+        let handle = thread::spawn(move || {
+            let mut guard = mutex.lock().unwrap();
+            locked_tx.send(()).unwrap();
+            thread::sleep(Duration::from_millis(5));
+            *guard += 1;
+        });
+        locked_rx.recv().unwrap();
+        handle
+    };
+
+    *flame::span_lock("contended", &mutex) += 1;
+    holder.join().unwrap();
+    *flame::span_lock("free", &mutex) += 1;
+
+    let spans = flame::spans();
+    assert!(spans[0].delta > 0);
+    assert_eq!(spans[0].notes[0].description.as_ref().unwrap(), "waited");
+    assert_eq!(spans[1].notes[0].description.as_ref().unwrap(), "available immediately");
+    assert_eq!(*mutex.lock().unwrap(), 3);
+}
+
+#[test]
+fn clear_current_thread() {
+    let _lock = exclusive();
+    flame::clear();
+    ::std::thread::spawn(|| {
+        flame::span_of("worker", || {});
+        flame::commit_thread();
+    }).join().unwrap();
+    flame::span_of("request", || {});
+
+    flame::clear_current_thread();
+    assert!(flame::spans().is_empty());
+    let threads = flame::threads();
+    assert_eq!(threads.len(), 2);
+    assert_eq!(threads[1].spans[0].name, "worker");
+    flame::clear();
+}
+
+#[test]
+fn json_version() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::span_of("versioned", || {});
+
+    let mut out = vec![];
+    flame::dump_json(&mut out).unwrap();
+    let mut json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(json["version"], 2);
+    assert_eq!(flame::read_threads_json(&out[..]).unwrap()[0].spans[0].name, "versioned");
+
+    // Version 1 didn't have metrics yet
+    let mut old = json.clone();
+    old["version"] = 1.into();
+    old["threads"][0]["spans"][0].as_object_mut().unwrap().remove("metrics");
+    assert_eq!(flame::read_threads_json(old.to_string().as_bytes()).unwrap()[0].spans[0].name, "versioned");
+
+    json["version"] = (flame::JSON_VERSION + 1).into();
+    let error = flame::read_threads_json(json.to_string().as_bytes()).unwrap_err();
+    assert!(error.to_string().contains("unsupported version"), "{}", error);
+}
+
+#[test]
+fn self_ns() {
+    let _lock = shared();
+    flame::clear();
+    let parent = flame::record_span("parent", 0, 100_000, None);
+    flame::record_span("first", 0, 30_000, Some(parent));
+    flame::record_span("second", 50_000, 80_000, Some(parent));
+
+    let spans = flame::spans();
+    assert_eq!(spans[0].self_ns(), 40_000);
+    assert_eq!(spans[0].children[0].self_ns(), 30_000);
+    flame::clear();
+}
+
+#[test]
+fn span_metrics() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::span_of("query", || {
+        flame::set_span_metric("rows", 500.0);
+        flame::set_span_metric("cache_hit_rate", 0.5);
+        flame::set_span_metric("cache_hit_rate", 0.9);
+    });
+
+    let spans = flame::spans();
+    assert_eq!(spans[0].metrics.len(), 2);
+    assert_eq!(spans[0].metrics["rows"], 500.0);
+
+    let mut out = vec![];
+    flame::dump_json(&mut out).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(json["threads"][0]["spans"][0]["metrics"], serde_json::json!({ "rows": 500.0, "cache_hit_rate": 0.9 }));
+    flame::clear();
+}
+
+#[test]
+fn global_mode() {
+    let _lock = exclusive();
+    use std::thread::spawn;
+
+    flame::set_global_mode(true);
+    flame::clear();
+    flame::span_of("main", || {
+        spawn(|| flame::span_of("callback", || flame::note("called", None::<&str>))).join().unwrap();
+    });
+    spawn(|| flame::span_of("worker", || {})).join().unwrap();
+
+    let spans = flame::spans();
+    let names: Vec<&str> = spans.iter().map(|span| &span.name[..]).collect();
+    assert_eq!(names, vec!["main", "worker"]);
+    assert_eq!(spans[0].children[0].name, "callback");
+    assert_eq!(spans[0].children[0].notes[0].name, "called");
+
+    flame::clear();
+    flame::set_global_mode(false);
+    assert!(flame::spans().is_empty());
+}
+
+#[test]
+fn merged_spans() {
+    let _lock = exclusive();
+    use std::thread::spawn;
+
+    flame::clear();
+    let workers: Vec<_> = (0 .. 2u64).map(|i| spawn(move || {
+        let job = flame::record_span("job", 0, 10_000 * (i + 1), None);
+        flame::record_span("parse", 0, 2_000, Some(job));
+        flame::record_span("query", 2_000, 5_000, Some(job));
+        flame::record_span("query", 5_000, 6_000, Some(job));
+        flame::commit_thread();
+    })).collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    let merged = flame::merged_spans();
+    assert_eq!(merged.len(), 1);
+    assert_eq!((merged[0].delta, merged[0].collapse_count), (30_000, 2));
+
+    let children: Vec<(&str, u64, u64)> = merged[0].children.iter()
+        .map(|child| (&child.name[..], child.delta, child.collapse_count))
+        .collect();
+    assert_eq!(children, vec![("parse", 4_000, 2), ("query", 8_000, 4)]);
+    assert_eq!(merged[0].children[1].depth, 1);
+    flame::clear();
+}
+
+#[test]
+fn text_dump_units() {
+    let _lock = exclusive();
+    flame::clear();
+    let outer = flame::record_span("outer", 0, 1_500_000, None);
+    flame::record_span("inner", 0, 500_000, Some(outer));
+
+    let dump = |unit| {
+        let mut out = vec![];
+        flame::dump_text_to_writer_unit(&mut out, unit).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    assert!(dump(flame::TimeUnit::Nanos).contains("| outer: 1500000ns"));
+    assert!(dump(flame::TimeUnit::Micros).contains("| inner: 500µs"));
+    assert!(dump(flame::TimeUnit::Micros).contains("+ 1000µs"));
+    assert!(dump(flame::TimeUnit::Millis).contains("| outer: 1.5ms"));
+    assert!(dump(flame::TimeUnit::Seconds).contains("| outer: 0.0015s"));
+    flame::clear();
+}
+
+#[test]
+fn commit_thread_as() {
+    let _lock = exclusive();
+    flame::clear();
+    ::std::thread::Builder::new().name("pool-1".into()).spawn(|| {
+        flame::span_of("parse", || {});
+        flame::commit_thread_as("parser");
+        flame::span_of("render", || {});
+        flame::commit_thread_as("renderer");
+    }).unwrap().join().unwrap();
+
+    let threads = flame::threads();
+    let names: Vec<Option<&str>> = threads.iter().map(|thread| thread.name.as_deref()).collect();
+    assert!(names.contains(&Some("parser")) && names.contains(&Some("renderer")), "{:?}", names);
+    assert!(!names.contains(&Some("pool-1")));
+    let parser = threads.iter().find(|thread| thread.name.as_deref() == Some("parser")).unwrap();
+    assert_eq!(parser.spans[0].name, "parse");
+    flame::clear();
+}
+
+#[test]
+fn prune_below() {
+    let _lock = shared();
+    flame::clear();
+    let root = flame::record_span("root", 0, 100_000, None);
+    let tiny = flame::record_span("tiny", 0, 1_000, Some(root));
+    flame::record_span("tinier", 0, 1_000, Some(tiny));
+    // A short parent with a significant child, e.g. from clock skew
+    let short = flame::record_span("short", 10_000, 11_000, Some(root));
+    flame::record_span("long", 10_000, 60_000, Some(short));
+    flame::record_span("noise", 100_000, 101_000, None);
+
+    let pruned = flame::prune_below(flame::spans(), 5_000);
+    assert_eq!(pruned.len(), 1);
+    assert_eq!(pruned[0].delta, 100_000);
+    let children: Vec<&str> = pruned[0].children.iter().map(|child| &child.name[..]).collect();
+    assert_eq!(children, vec!["short"]);
+    assert_eq!(pruned[0].children[0].children[0].name, "long");
+    flame::clear();
+}
+
+#[test]
+fn snapshot() {
+    let _lock = exclusive();
+    use std::thread::spawn;
+
+    flame::clear();
+    flame::span_of("main", || {});
+    let worker = spawn(|| {
+        flame::span_of("worker", || {});
+        let id = flame::threads()[0].id;
+        flame::commit_thread();
+        id
+    }).join().unwrap();
+
+    let snapshot = flame::snapshot();
+    flame::span_of("after", || {});
+
+    let threads = snapshot.all_threads();
+    assert_eq!(threads.len(), 2);
+    assert_eq!(threads[0].spans.len(), 1);
+    assert_eq!(snapshot.spans_for(worker).unwrap()[0].name, "worker");
+    assert_eq!(snapshot.spans_for(threads[0].id).unwrap()[0].name, "main");
+    assert!(snapshot.spans_for(usize::MAX).is_none());
+    flame::clear();
+}
+
+#[test]
+fn dump_dot() {
+    let _lock = exclusive();
+    flame::clear();
+    let root = flame::record_span("root", 0, 2_000_000, None);
+    flame::record_span("say \"hi\"", 0, 1_500_000, Some(root));
+
+    let mut out = vec![];
+    flame::dump_dot_to_writer(&mut out, &flame::spans()).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+
+    assert_eq!(lines[0], "digraph flame {");
+    assert_eq!(lines[2], r##"    n0 [label="root\n2ms", fillcolor="#ffaaaa"];"##);
+    assert_eq!(lines[3], r##"    n1 [label="say \"hi\"\n1.5ms", fillcolor="#ff0000"];"##);
+    assert_eq!(lines[4], "    n0 -> n1;");
+    assert_eq!(lines[5], "}");
+    flame::clear();
+}
+
+#[test]
+fn dump_csv() {
+    let _lock = exclusive();
+    flame::clear();
+    let root = flame::record_span("load, then parse", 0, 100_000, None);
+    flame::record_span("say \"hi\"", 10_000, 40_000, Some(root));
+
+    let mut out = vec![];
+    flame::dump_csv_to_writer(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    let id = flame::threads()[0].id;
+
+    assert_eq!(lines[0], "thread_id,depth,name,start_ns,end_ns,delta_ns,self_ns,note_count");
+    assert!(lines[1].starts_with(&format!("{},0,\"load, then parse\",", id)), "{}", lines[1]);
+    assert!(lines[1].ends_with(",100000,70000,0"), "{}", lines[1]);
+    assert!(lines[2].starts_with(&format!("{},1,\"say \"\"hi\"\"\",", id)), "{}", lines[2]);
+    assert!(lines[2].ends_with(",30000,30000,0"), "{}", lines[2]);
+    flame::clear();
+}
+
+#[test]
+fn unclosed_spans() {
+    let _lock = exclusive();
+    use std::thread::spawn;
+
+    flame::clear();
+    let names = spawn(|| {
+        flame::start("outer");
+        flame::start("inner");
+        flame::unclosed_spans()
+    }).join().unwrap();
+    assert_eq!(names, vec!["outer", "inner"]);
+    assert!(flame::threads().iter().all(|thread| thread.spans.is_empty()));
+
+    flame::set_close_on_commit(true);
+    spawn(|| {
+        flame::start("forgotten");
+        flame::span_of("done", || {});
+    }).join().unwrap();
+    flame::set_close_on_commit(false);
+
+    let threads = flame::threads();
+    let span = threads.iter().flat_map(|thread| &thread.spans).next().unwrap();
+    assert_eq!(span.name, "forgotten");
+    assert_eq!(span.children[0].name, "done");
+    assert_eq!((&span.notes[0].name[..], span.notes[0].level), ("unclosed", flame::NoteLevel::Warn));
+    flame::clear();
+}
+
+#[test]
+fn sample_rate() {
+    let _lock = exclusive();
+    flame::clear();
+    flame::set_sample_rate(3);
+    for i in 0 .. 7 {
+        flame::span_of(format!("iteration {}", i), || {
+            flame::note("inside", None::<&str>);
+            flame::span_of("child", || flame::attr("i", flame::AttrValue::U64(i)));
+        });
+    }
+    flame::set_sample_rate(1);
+
+    let spans = flame::spans();
+    let names: Vec<&str> = spans.iter().map(|span| &span.name[..]).collect();
+    assert_eq!(names, vec!["iteration 0", "iteration 3", "iteration 6"]);
+    assert_eq!(spans[1].children[0].attributes[0].1, flame::AttrValue::U64(3));
+    assert!(flame::unclosed_spans().is_empty());
+    flame::clear();
+}
+
+#[test]
+fn threads_sorted() {
+    let _lock = exclusive();
+    use std::thread::Builder;
+
+    flame::clear();
+    for name in &["worker-b", "worker-a", "worker-c", "worker-a"] {
+        Builder::new().name(name.to_string()).spawn(|| {
+            flame::span_of("work", || {});
+        }).unwrap().join().unwrap();
+    }
+
+    let threads = flame::threads_sorted();
+    let names: Vec<&str> = threads.iter().filter_map(|thread| thread.name.as_deref())
+        .filter(|name| name.starts_with("worker"))
+        .collect();
+    assert_eq!(names, vec!["worker-a", "worker-a", "worker-b", "worker-c"]);
+    assert!(threads.windows(2).all(|pair| (&pair[0].name, pair[0].id) <= (&pair[1].name, pair[1].id)));
+    flame::clear();
+}
@@ -0,0 +1,35 @@
+#![cfg(feature = "testutil")]
+
+extern crate flame;
+
+fn record() -> Vec<flame::Span> {
+    flame::clear();
+    flame::span_of("a", || {
+        flame::span_of("b", || {
+            flame::span_of("c", || {});
+        });
+        flame::span_of("d", || {});
+    });
+    flame::spans()
+}
+
+#[test]
+fn expect_span_found() {
+    let spans = record();
+    let c = flame::expect_span(&spans, &["a", "b", "c"]);
+    assert_eq!((c.name.as_ref(), c.depth), ("c", 2));
+}
+
+#[test]
+#[should_panic(expected = r#"no child "x" under "a"; available: ["b", "d"]"#)]
+fn expect_span_missing_child() {
+    let spans = record();
+    flame::expect_span(&spans, &["a", "x", "c"]);
+}
+
+#[test]
+#[should_panic(expected = r#"no top-level span "b"; available: ["a"]"#)]
+fn expect_span_missing_root() {
+    let spans = record();
+    flame::expect_span(&spans, &["b"]);
+}